@@ -1,22 +1,36 @@
 use modules::{
     database::{database::DbConnection, types::*},
-    web_client::server::WebServer,
+    web_client::{
+        protocol,
+        rest::RestServer,
+        server::{Registrar, WebServer},
+    },
 };
 use std::sync::mpsc::{channel, Receiver, Sender};
 
 mod modules;
 
 fn main() {
+    // `ai_assistant schema` prints the JSON Schema of the WebSocket protocol.
+    if std::env::args().nth(1).as_deref() == Some("schema") {
+        println!("{:#}", protocol::schema());
+        return;
+    }
     let (
         (id_sender, id_receiver),
         (network_sender, network_receiver),
         (channel_sender, channel_receiver),
     ) = create_channels();
     let mut database = DbConnection::new(network_receiver, id_receiver, channel_sender);
-    let mut server = WebServer::new(id_sender, network_sender, channel_receiver);
+    let registrar = Registrar::new(id_sender, channel_receiver);
+    let mut server = WebServer::new(registrar.clone(), network_sender.clone());
+    let mut rest_server = RestServer::new(registrar, network_sender);
     std::thread::spawn(move || {
         server.update();
     });
+    std::thread::spawn(move || {
+        rest_server.update();
+    });
     std::thread::spawn(move || {
         database.update();
     });
@@ -26,10 +40,7 @@ fn main() {
 fn create_channels() -> (
     (Sender<String>, Receiver<String>),
     (Sender<NetworkMessage>, Receiver<NetworkMessage>),
-    (
-        Sender<Receiver<DatabaseMessage>>,
-        Receiver<Receiver<DatabaseMessage>>,
-    ),
+    (Sender<Channels>, Receiver<Channels>),
 ) {
     let id_channel = channel();
     let network_channel = channel();
@@ -1,3 +1,5 @@
 pub mod database;
 pub mod env;
+pub mod password;
+pub mod tokenizer;
 pub mod web_client;
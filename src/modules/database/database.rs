@@ -1,6 +1,10 @@
 use crate::modules::{
-    database::types::*,
-    web_client::types::{Message, UserInfo, WebMessage},
+    database::{migrations, types::*},
+    web_client::types::{
+        ApiKey, ChatEntry, ChatPrompt, ChatSettings, ChatTitle, CreateApiKey, Credentials,
+        Direction, Message, MessagePage, NewApiKey, PageRequest, Persona, Sampling, Scope,
+        SearchResult, Session, Summary, UserInfo, WebMessage,
+    },
 };
 use sha2::Digest;
 use sqlite::Connection;
@@ -9,33 +13,77 @@ use std::{
     sync::mpsc::{channel, Receiver, Sender},
 };
 
+// Access tokens are short-lived. Clients renew them with the refresh token,
+// which slides forward on every renewal and is rotated each time.
+const ACCESS_TOKEN_LIFETIME: u64 = 60 * 15;
+const REFRESH_TOKEN_LIFETIME: u64 = 60 * 60 * 24 * 30;
+
 enum ValidationError {
     InvalidCredentials,
     TokenExpired,
 }
 
+// What an authenticated connection acts for.
+enum Credential {
+    Session(String),
+    ApiKey(String),
+}
+
+// What a request takes beyond an authenticated connection, for connections
+// that authenticated with an API key.
+enum Requirement {
+    Scope(Scope),
+    // Managing sessions and keys is left to people that signed in.
+    Session,
+}
+
+// Where a new message hangs in the chat tree and how it was generated.
+#[derive(Default)]
+struct Origin<'a> {
+    parent: Option<&'a str>,
+    model: Option<&'a str>,
+    truncated: bool,
+}
+
 pub struct DbConnection {
     connection: Connection,
     senders: HashMap<String, Sender<DatabaseMessage>>,
+    // The push channel of every connection, see `Channels`.
+    push_senders: HashMap<String, Sender<DatabaseMessage>>,
     email_senders: HashMap<String, HashMap<String, Sender<DatabaseMessage>>>,
+    // The session or API key each authenticated connection acts for.
+    connections: HashMap<String, Credential>,
     receiver: Receiver<NetworkMessage>,
     nreceiver: Receiver<String>,
-    receiver_sender: Sender<Receiver<DatabaseMessage>>,
+    receiver_sender: Sender<Channels>,
 }
 
 impl DbConnection {
     pub fn new(
         receiver: Receiver<NetworkMessage>,
         nreceiver: Receiver<String>,
-        receiver_sender: Sender<Receiver<DatabaseMessage>>,
+        receiver_sender: Sender<Channels>,
+    ) -> Self {
+        let connection = Connection::open("database").unwrap();
+        Self::with_connection(connection, receiver, nreceiver, receiver_sender)
+    }
+
+    fn with_connection(
+        connection: Connection,
+        receiver: Receiver<NetworkMessage>,
+        nreceiver: Receiver<String>,
+        receiver_sender: Sender<Channels>,
     ) -> Self {
+        migrations::migrate(&connection).expect("Unable to migrate database");
         Self {
             receiver,
             nreceiver,
             receiver_sender,
             senders: HashMap::new(),
+            push_senders: HashMap::new(),
             email_senders: HashMap::new(),
-            connection: Connection::open("database").unwrap(),
+            connections: HashMap::new(),
+            connection,
         }
     }
 
@@ -48,23 +96,46 @@ impl DbConnection {
 
     fn receive_new_connections(&mut self) {
         if let Ok(id) = self.nreceiver.try_recv() {
-            let (sender, receiver) = channel();
-            self.senders.insert(id, sender);
-            let _ = self.receiver_sender.send(receiver);
+            let (sender, replies) = channel();
+            let (push_sender, pushes) = channel();
+            self.senders.insert(id.clone(), sender);
+            self.push_senders.insert(id, push_sender);
+            let _ = self.receiver_sender.send(Channels { replies, pushes });
         }
     }
 
     fn receive_messages(&mut self) {
         while let Ok(message) = self.receiver.try_recv() {
+            if let Some((id, requirement)) = requirement(&message) {
+                if !self.permits(id, &requirement) {
+                    // Requests that get no answer are dropped quietly.
+                    if !matches!(
+                        message,
+                        NetworkMessage::RecordAudioPath(..) | NetworkMessage::SaveSummary(..)
+                    ) {
+                        let sender = self.senders.get(id).unwrap();
+                        let _ = sender.send(DatabaseMessage::MissingScope);
+                    }
+                    continue;
+                }
+            }
             match message {
-                NetworkMessage::LoginRequest(ref id, ref email, ref password_hash) => {
-                    let sender = self.senders.get(id).unwrap();
-                    let message = if let Some(token) =
-                        self.validate_connection(email, password_hash)
+                NetworkMessage::LoginRequest(ref id, ref email, ref rehash, ref device) => {
+                    let sender = self.senders.get(id).unwrap().clone();
+                    if let Some(ref password_hash) = rehash {
+                        self.set_password_hash(email, password_hash);
+                    }
+                    let message = if let Some(credentials) =
+                        self.validate_connection(email, device.as_deref())
                     {
                         let name = self.get_user_name(email);
                         if let Some(name) = name {
-                            DatabaseMessage::UserInfo(UserInfo::new(email.to_string(), name, token))
+                            let _ = self.bind(id, &credentials.token, true);
+                            DatabaseMessage::UserInfo(UserInfo::new(
+                                email.to_string(),
+                                name,
+                                credentials,
+                            ))
                         } else {
                             DatabaseMessage::Err
                         }
@@ -73,77 +144,205 @@ impl DbConnection {
                     };
                     let _ = sender.send(message);
                 }
-                NetworkMessage::TokenValidation(ref id, ref token) => {
+                NetworkMessage::Refresh(ref id, ref refresh_token) => {
+                    let sender = self.senders.get(id).unwrap().clone();
+                    match self.refresh(refresh_token) {
+                        Some(credentials) => {
+                            let _ = self.bind(id, &credentials.token, true);
+                            let _ = sender.send(DatabaseMessage::Token(credentials));
+                        }
+                        None => {
+                            let _ = sender.send(DatabaseMessage::Err);
+                        }
+                    }
+                }
+                NetworkMessage::GetPasswordHash(ref id, ref email) => {
                     let sender = self.senders.get(id).unwrap();
-                    let email = self.validate_token(token);
-                    if let Some(email) = email {
-                        let _ = sender.send(DatabaseMessage::Email(email.clone()));
-                        let entry = self.email_senders.entry(email).or_insert(HashMap::new());
-                        entry.insert(id.to_string(), sender.clone());
-                    } else {
-                        let _ = sender.send(DatabaseMessage::Err);
+                    match self.get_password_hash(email) {
+                        Some(password_hash) => {
+                            let _ = sender.send(DatabaseMessage::PasswordHash(password_hash));
+                        }
+                        None => {
+                            let _ = sender.send(DatabaseMessage::Err);
+                        }
+                    }
+                }
+                NetworkMessage::Authenticate(ref id, ref token)
+                | NetworkMessage::Authorize(ref id, ref token) => {
+                    let sender = self.senders.get(id).unwrap().clone();
+                    let pushes = matches!(message, NetworkMessage::Authenticate(..));
+                    match self.bind(id, token, pushes) {
+                        Ok(email) => {
+                            let _ = sender.send(DatabaseMessage::Email(email));
+                        }
+                        Err(ValidationError::TokenExpired) => {
+                            let _ = sender.send(DatabaseMessage::TokenExpired);
+                        }
+                        Err(ValidationError::InvalidCredentials) => {
+                            let _ = sender.send(DatabaseMessage::Err);
+                        }
+                    }
+                }
+                NetworkMessage::CreateApiKey(ref id, ref request) => {
+                    let sender = self.senders.get(id).unwrap();
+                    match self.authorize(id) {
+                        Some(ref email) => {
+                            let key = self.create_api_key(email, request);
+                            let _ = sender.send(DatabaseMessage::ApiKeyCreated(key));
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
+                }
+                NetworkMessage::GetApiKeys(ref id) => {
+                    let sender = self.senders.get(id).unwrap();
+                    match self.authorize(id) {
+                        Some(ref email) => {
+                            let keys = self.get_api_keys(email);
+                            let _ = sender.send(DatabaseMessage::ApiKeys(keys));
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
+                }
+                NetworkMessage::RevokeApiKey(ref id, ref key_id) => {
+                    let sender = self.senders.get(id).unwrap().clone();
+                    match self.authorize(id) {
+                        Some(ref email) => {
+                            let revoked = self.revoke_api_key(email, key_id);
+                            let keys = self.get_api_keys(email);
+                            let _ = sender.send(DatabaseMessage::ApiKeys(keys));
+                            if revoked {
+                                self.disconnect(&[key_id.to_string()]);
+                            }
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
+                }
+                NetworkMessage::CheckScope(ref id, _, ref chat_id) => {
+                    let sender = self.senders.get(id).unwrap();
+                    let email = match chat_id {
+                        Some(chat_id) => self.authorize_chat(id, chat_id),
+                        None => self.authorize(id),
+                    };
+                    match email {
+                        Some(_) => {
+                            let _ = sender.send(DatabaseMessage::Ok);
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
                     }
                 }
-                NetworkMessage::NewChat(ref _id, ref email) => {
-                    let chat_id = self.new_chat(email);
-                    if let Some(senders) = self.email_senders.get(email) {
-                        for (_rid, sender) in senders {
-                            let _ = sender.send(DatabaseMessage::NewChat(chat_id.clone()));
+                NetworkMessage::NewChat(ref id) => {
+                    let Some(email) = self.authorize(id) else {
+                        let sender = self.senders.get(id).unwrap();
+                        let _ = sender.send(self.auth_error(id));
+                        continue;
+                    };
+                    let chat_id = self.new_chat(&email);
+                    let sender = self.senders.get(id).unwrap();
+                    let _ = sender.send(DatabaseMessage::NewChat(chat_id.clone()));
+                    if let Some(senders) = self.email_senders.get(&email) {
+                        for (rid, sender) in senders {
+                            if rid != id {
+                                let _ = sender.send(DatabaseMessage::NewChat(chat_id.clone()));
+                            }
                         }
                     }
                 }
-                NetworkMessage::ChatRequest(ref id, ref token, ref chat_id) => {
+                NetworkMessage::ChatRequest(ref id, ref chat_id) => {
                     let sender = self.senders.get(id).unwrap();
-                    let email = self.validate_token(token);
+                    let email = self.authorize_chat(id, chat_id);
                     if let Some(ref email) = email {
                         let messages = self.get_chat_messages(email, chat_id);
                         let _ =
                             sender.send(DatabaseMessage::Messages(chat_id.to_string(), messages));
                     } else {
-                        let _ = sender.send(DatabaseMessage::Err);
+                        let _ = sender.send(self.auth_error(id));
+                    }
+                }
+                NetworkMessage::GetMessagePage(ref id, ref chat_id, ref page) => {
+                    let sender = self.senders.get(id).unwrap();
+                    let email = self.authorize_chat(id, chat_id);
+                    match email.and_then(|email| self.get_message_page(&email, chat_id, page)) {
+                        Some(page) => {
+                            let _ = sender.send(DatabaseMessage::MessagePage(page));
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
                     }
                 }
                 NetworkMessage::NewMessage(
                     ref id,
-                    ref token,
                     ref chat_sender,
                     ref chat_id,
                     ref content,
                     ref message_id,
+                    truncated,
+                    ref model,
                 ) => {
                     let sender = self.senders.get(id).unwrap();
-                    let email = self.validate_token(token);
+                    let email = self.authorize_chat(id, chat_id);
                     if let Some(ref email) = email {
-                        let timestamp =
-                            self.new_chat_message(email, chat_sender, chat_id, content, message_id);
+                        let parent = self.chat_head(email, chat_id);
+                        let origin = Origin {
+                            parent: parent.as_deref(),
+                            model: model.as_deref(),
+                            truncated,
+                        };
+                        let timestamp = self.new_chat_message(
+                            email,
+                            chat_sender,
+                            chat_id,
+                            content,
+                            message_id,
+                            origin,
+                        );
                         let _ = sender.send(DatabaseMessage::Timestamp(timestamp));
                         if let Some(senders) = self.email_senders.get(email) {
                             for (rid, sender) in senders {
                                 if rid != id {
-                                    let _ =
-                                        sender.send(DatabaseMessage::WebMessage(WebMessage::new(
-                                            Message::new(chat_sender, content),
-                                            timestamp,
-                                            message_id.to_string(),
-                                        )));
+                                    let mut message = WebMessage::new(
+                                        Message::new(chat_sender, content),
+                                        timestamp,
+                                        message_id.to_string(),
+                                    );
+                                    message.truncated = truncated;
+                                    message.model = model.clone();
+                                    message.parent_id = parent.clone();
+                                    let _ = sender.send(DatabaseMessage::WebMessage(message));
                                 }
                             }
                         }
                     } else {
-                        let _ = sender.send(DatabaseMessage::Err);
+                        let _ = sender.send(self.auth_error(id));
                     }
                 }
-                NetworkMessage::GetChats(ref id, ref email) => {
+                NetworkMessage::GetChats(ref id) => {
                     let sender = self.senders.get(id).unwrap();
-                    let chats = self.get_chats(email);
-                    let _ = sender.send(DatabaseMessage::Chats(chats));
+                    match self.authorize(id) {
+                        Some(ref email) => {
+                            let chats = self.get_chats(email);
+                            let _ = sender.send(DatabaseMessage::Chats(chats));
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
                 }
-                NetworkMessage::DeleteChat(ref id, ref token, ref chat_id) => {
+                NetworkMessage::DeleteChat(ref id, ref chat_id) => {
                     let sender = self.senders.get(id).unwrap();
-                    let email = self.validate_token(token);
+                    let email = self.authorize_chat(id, chat_id);
                     if let Some(ref email) = email {
                         self.delete_chat(email, chat_id);
                         self.delete_messages(email, chat_id);
+                        self.delete_summary(email, chat_id);
                         let _ = sender.send(DatabaseMessage::Deleted(chat_id.clone()));
                         if let Some(senders) = self.email_senders.get(email) {
                             for (rid, sender) in senders {
@@ -153,22 +352,31 @@ impl DbConnection {
                             }
                         }
                     } else {
-                        let _ = sender.send(DatabaseMessage::Err);
+                        let _ = sender.send(self.auth_error(id));
                     }
                 }
-                NetworkMessage::RegisterUser(ref id, ref name, ref email, ref password) => {
-                    let sender = self.senders.get(id).unwrap();
+                NetworkMessage::RegisterUser(
+                    ref id,
+                    ref name,
+                    ref email,
+                    ref password_hash,
+                    ref device,
+                ) => {
+                    let sender = self.senders.get(id).unwrap().clone();
                     if self.user_exists(email) {
                         let _ = sender.send(DatabaseMessage::Err);
                         return;
                     }
-                    self.register_user(name, email, password);
-                    let token = self.create_token(email);
-                    let _ = sender.send(DatabaseMessage::Token(token));
+                    self.register_user(name, email, password_hash);
+                    let credentials = self.create_token(email, device.as_deref());
+                    let _ = self.bind(id, &credentials.token, true);
+                    let _ = sender.send(DatabaseMessage::Token(credentials));
                 }
                 NetworkMessage::GetMessage(ref id, ref message_id) => {
                     let sender = self.senders.get(id).unwrap();
-                    let result = self.get_message(message_id);
+                    let result = self
+                        .authorize_message(id, message_id)
+                        .and_then(|email| self.get_message(&email, message_id));
                     if let Some(ref message) = result {
                         let _ = sender.send(DatabaseMessage::Message(Message::new("", message)));
                         return;
@@ -177,15 +385,260 @@ impl DbConnection {
                 }
                 NetworkMessage::GetAudioPath(ref id, ref message_id) => {
                     let sender = self.senders.get(id).unwrap();
-                    let result = self.get_audio_path(message_id);
+                    let result = self
+                        .authorize_message(id, message_id)
+                        .and_then(|email| self.get_audio_path(&email, message_id));
                     if let Some(path) = result {
                         let _ = sender.send(DatabaseMessage::AudioPath(path));
                         return;
                     }
                     let _ = sender.send(DatabaseMessage::Err);
                 }
-                NetworkMessage::RecordAudioPath(ref message_id, ref path) => {
-                    self.record_audio_path(message_id, path)
+                NetworkMessage::RecordAudioPath(ref id, ref message_id, ref path) => {
+                    if self.authorize_message(id, message_id).is_some() {
+                        self.record_audio_path(message_id, path);
+                    }
+                }
+                NetworkMessage::GetSummary(ref id, ref chat_id) => {
+                    let sender = self.senders.get(id).unwrap();
+                    let summary = self
+                        .authorize_chat(id, chat_id)
+                        .and_then(|email| self.get_summary(&email, chat_id));
+                    match summary {
+                        Some(summary) => {
+                            let _ = sender.send(DatabaseMessage::Summary(summary));
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
+                }
+                NetworkMessage::SaveSummary(ref id, ref chat_id, ref summary) => {
+                    if let Some(email) = self.authorize_chat(id, chat_id) {
+                        self.save_summary(&email, chat_id, summary);
+                    }
+                }
+                NetworkMessage::GetSystemPrompt(ref id, ref chat_id) => {
+                    let sender = self.senders.get(id).unwrap();
+                    let prompt = self
+                        .authorize_chat(id, chat_id)
+                        .and_then(|email| self.get_system_prompt(&email, chat_id));
+                    match prompt {
+                        Some(prompt) => {
+                            let _ = sender.send(DatabaseMessage::SystemPrompt(prompt));
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
+                }
+                NetworkMessage::SetSystemPrompt(ref id, ref prompt) => {
+                    let sender = self.senders.get(id).unwrap();
+                    let email = self.authorize_chat(id, &prompt.chat_id);
+                    if let Some(ref email) = email {
+                        if self.set_system_prompt(email, prompt) {
+                            let _ = sender.send(DatabaseMessage::ChatPrompt(prompt.clone()));
+                            continue;
+                        }
+                    }
+                    let _ = sender.send(self.auth_error(id));
+                }
+                NetworkMessage::RenameChat(ref id, ref title, generated) => {
+                    let sender = self.senders.get(id).unwrap();
+                    let email = self.authorize_chat(id, &title.chat_id);
+                    if let Some(ref email) = email {
+                        if self.rename_chat(email, title, generated) {
+                            let _ = sender.send(DatabaseMessage::ChatTitle(title.clone()));
+                            if let Some(senders) = self.email_senders.get(email) {
+                                for (rid, sender) in senders {
+                                    if rid != id {
+                                        let _ =
+                                            sender.send(DatabaseMessage::ChatTitle(title.clone()));
+                                    }
+                                }
+                            }
+                            continue;
+                        }
+                    }
+                    let _ = sender.send(self.auth_error(id));
+                }
+                NetworkMessage::Search(ref id, ref query, limit) => {
+                    let sender = self.senders.get(id).unwrap();
+                    match self.authorize(id) {
+                        Some(ref email) => {
+                            let results = self.search(email, query, limit);
+                            let _ = sender.send(DatabaseMessage::SearchResults(results));
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
+                }
+                NetworkMessage::EditMessage(
+                    ref id,
+                    ref chat_id,
+                    ref message_id,
+                    ref new_id,
+                    ref content,
+                ) => {
+                    let sender = self.senders.get(id).unwrap();
+                    let email = self.authorize_chat(id, chat_id);
+                    if let Some(ref email) = email {
+                        if let Some(message) =
+                            self.edit_message(email, chat_id, message_id, new_id, content)
+                        {
+                            let _ = sender.send(DatabaseMessage::WebMessage(message.clone()));
+                            if let Some(senders) = self.email_senders.get(email) {
+                                for (rid, sender) in senders {
+                                    if rid != id {
+                                        let _ = sender
+                                            .send(DatabaseMessage::WebMessage(message.clone()));
+                                    }
+                                }
+                            }
+                            continue;
+                        }
+                    }
+                    let _ = sender.send(self.auth_error(id));
+                }
+                NetworkMessage::SwitchBranch(ref id, ref chat_id, ref message_id) => {
+                    let sender = self.senders.get(id).unwrap();
+                    let email = self.authorize_chat(id, chat_id);
+                    if let Some(ref email) = email {
+                        if self.switch_branch(email, chat_id, message_id) {
+                            let _ = sender.send(DatabaseMessage::Ok);
+                            continue;
+                        }
+                    }
+                    let _ = sender.send(self.auth_error(id));
+                }
+                NetworkMessage::Regenerate(ref id, ref chat_id) => {
+                    let sender = self.senders.get(id).unwrap();
+                    let rewound = self
+                        .authorize_chat(id, chat_id)
+                        .and_then(|email| self.rewind(&email, chat_id));
+                    match rewound {
+                        Some((message, answers, head)) => {
+                            let _ = sender.send(DatabaseMessage::Rewound(message, answers, head));
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
+                }
+                NetworkMessage::GetSessions(ref id) => {
+                    let sender = self.senders.get(id).unwrap();
+                    match self.authorize(id) {
+                        Some(ref email) => {
+                            let sessions = self.get_sessions(email, id);
+                            let _ = sender.send(DatabaseMessage::Sessions(sessions));
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
+                }
+                NetworkMessage::RevokeSession(ref id, ref session_id) => {
+                    let sender = self.senders.get(id).unwrap().clone();
+                    match self.authorize(id) {
+                        Some(ref email) => {
+                            let revoked = self.revoke_session(email, session_id);
+                            let sessions = self.get_sessions(email, id);
+                            let _ = sender.send(DatabaseMessage::Sessions(sessions));
+                            if revoked {
+                                self.disconnect(&[session_id.to_string()]);
+                            }
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
+                }
+                NetworkMessage::LogoutAll(ref id) => {
+                    let sender = self.senders.get(id).unwrap().clone();
+                    match self.authorize(id) {
+                        Some(ref email) => {
+                            let sessions: Vec<String> = self
+                                .get_sessions(email, id)
+                                .into_iter()
+                                .map(|x| x.id)
+                                .collect();
+                            self.delete_tokens(email);
+                            let _ = sender.send(DatabaseMessage::Sessions(Vec::new()));
+                            self.disconnect(&sessions);
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
+                }
+                NetworkMessage::Disconnect(ref id) => {
+                    self.unbind(id);
+                    self.senders.remove(id);
+                    self.push_senders.remove(id);
+                }
+                NetworkMessage::GetChatSettings(ref id, ref chat_id) => {
+                    let sender = self.senders.get(id).unwrap();
+                    let settings = self
+                        .authorize_chat(id, chat_id)
+                        .and_then(|email| self.get_chat_settings(&email, chat_id));
+                    match settings {
+                        Some(settings) => {
+                            let _ = sender.send(DatabaseMessage::ChatSettings(settings));
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
+                }
+                NetworkMessage::SetChatSettings(ref id, ref settings) => {
+                    let sender = self.senders.get(id).unwrap();
+                    let email = self.authorize_chat(id, &settings.chat_id);
+                    if let Some(ref email) = email {
+                        if self.set_chat_settings(email, settings) {
+                            let _ = sender.send(DatabaseMessage::ChatSettings(settings.clone()));
+                            continue;
+                        }
+                    }
+                    let _ = sender.send(self.auth_error(id));
+                }
+                NetworkMessage::GetPersonas(ref id) => {
+                    let sender = self.senders.get(id).unwrap();
+                    match self.authorize(id) {
+                        Some(ref email) => {
+                            let _ =
+                                sender.send(DatabaseMessage::Personas(self.get_personas(email)));
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
+                }
+                NetworkMessage::SavePersona(ref id, ref persona) => {
+                    let sender = self.senders.get(id).unwrap();
+                    match self.authorize(id) {
+                        Some(ref email) => {
+                            self.save_persona(email, persona);
+                            let _ =
+                                sender.send(DatabaseMessage::Personas(self.get_personas(email)));
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
+                }
+                NetworkMessage::DeletePersona(ref id, ref name) => {
+                    let sender = self.senders.get(id).unwrap();
+                    match self.authorize(id) {
+                        Some(ref email) => {
+                            self.delete_persona(email, name);
+                            let _ =
+                                sender.send(DatabaseMessage::Personas(self.get_personas(email)));
+                        }
+                        None => {
+                            let _ = sender.send(self.auth_error(id));
+                        }
+                    }
                 }
             }
         }
@@ -211,10 +664,10 @@ impl DbConnection {
         statement.iter().count();
     }
 
-    fn get_audio_path(&self, message_id: &str) -> Option<String> {
-        let query = "select path from AudioPaths where id = ?";
+    fn get_audio_path(&self, email: &str, message_id: &str) -> Option<String> {
+        let query = "select path from AudioPaths join Messages on Messages.id = AudioPaths.id where AudioPaths.id = ? and Messages.email = ?";
         let mut statement = self.connection.prepare(query).unwrap();
-        statement.bind((1, message_id)).unwrap();
+        statement.bind_iter([(1, message_id), (2, email)]).unwrap();
         for result in statement.into_iter() {
             if let Ok(row) = result {
                 let path = row.read::<&str, _>("path");
@@ -224,11 +677,11 @@ impl DbConnection {
         None
     }
 
-    fn get_message(&self, message_id: &str) -> Option<String> {
+    fn get_message(&self, email: &str, message_id: &str) -> Option<String> {
         println!("{message_id}");
-        let query = "select content from Messages where id = ?";
+        let query = "select content from Messages where id = ? and email = ?";
         let mut statement = self.connection.prepare(query).unwrap();
-        statement.bind((1, message_id)).unwrap();
+        statement.bind_iter([(1, message_id), (2, email)]).unwrap();
         for result in statement.into_iter() {
             if let Ok(row) = result {
                 let message = row.read::<&str, _>("content");
@@ -245,176 +698,996 @@ impl DbConnection {
         statement.iter().count();
     }
 
-    fn delete_chat(&self, email: &str, chat_id: &str) {
-        let query = "delete from Chats where email = ? and chat_id = ?";
+    // A persona takes precedence over the chat's own prompt, falling back to it
+    // when the persona has been deleted from the library.
+    fn get_system_prompt(&self, email: &str, chat_id: &str) -> Option<String> {
+        let query = "select Chats.system_prompt as system_prompt, Personas.prompt as persona_prompt from Chats left join Personas on Personas.email = Chats.email and Personas.name = Chats.persona where Chats.email = ? and Chats.chat_id = ?";
         let mut statement = self.connection.prepare(query).unwrap();
         statement.bind_iter([(1, email), (2, chat_id)]).unwrap();
-        statement.iter().count();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                let persona = row.read::<Option<&str>, _>("persona_prompt");
+                let prompt = row.read::<Option<&str>, _>("system_prompt");
+                return persona
+                    .or(prompt)
+                    .filter(|x| !x.trim().is_empty())
+                    .map(|x| x.to_string());
+            }
+        }
+        None
     }
 
-    fn new_chat_message(
-        &self,
-        email: &str,
-        sender: &str,
-        chat_id: &str,
-        content: &str,
-        message_id: &str,
-    ) -> u64 {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let query = "insert into Messages values (?, ?, ?, ?, ?, ?)";
+    fn set_system_prompt(&self, email: &str, prompt: &ChatPrompt) -> bool {
+        let query =
+            "update Chats set system_prompt = ?, persona = ? where email = ? and chat_id = ?";
         let mut statement = self.connection.prepare(query).unwrap();
         statement
             .bind_iter([
-                (1, email),
-                (2, chat_id),
-                (3, sender),
-                (4, content),
-                (5, &now.to_string()),
-                (6, message_id),
+                (1, prompt.system_prompt.as_deref()),
+                (2, prompt.persona.as_deref()),
+                (3, Some(email)),
+                (4, Some(prompt.chat_id.as_str())),
             ])
-            .expect("coulndt fit now");
-        println!("{}", statement.iter().count());
-        now
+            .unwrap();
+        statement.iter().count();
+        self.connection.change_count() == 1
     }
 
-    fn get_chat_messages(&self, email: &str, chat_id: &str) -> Vec<WebMessage> {
-        println!("{email} {chat_id}");
-        let query =
-            "select * from Messages where email = ? and chat_id = ? order by datetime limit 50";
-        let mut statement = self.connection.prepare(query).unwrap();
-        statement.bind_iter([(1, email), (2, chat_id)]).unwrap();
-        let mut messages = Vec::new();
+    fn search(&self, email: &str, query: &str, limit: u64) -> Vec<SearchResult> {
+        let Some(query) = fts_query(query) else {
+            return Vec::new();
+        };
+        let query_sql = "select Messages.chat_id as chat_id, Messages.id as id, Messages.datetime as datetime, Chats.title as title, snippet(MessagesSearch, 0, '**', '**', '...', 12) as snippet from MessagesSearch join Messages on Messages.rowid = MessagesSearch.rowid left join Chats on Chats.email = Messages.email and Chats.chat_id = Messages.chat_id where MessagesSearch match ? and Messages.email = ? order by rank limit ?";
+        let mut statement = self.connection.prepare(query_sql).unwrap();
+        statement.bind((1, query.as_str())).unwrap();
+        statement.bind((2, email)).unwrap();
+        statement.bind((3, limit as i64)).unwrap();
+        let mut results = Vec::new();
         for result in statement.into_iter() {
             if let Ok(row) = result {
-                let sender = row.read::<&str, _>("sender");
-                let content = row.read::<&str, _>("content");
-                let timestamp = row.read::<i64, _>("datetime");
-                let id = row.read::<&str, _>("id");
-                let message = Message::new(sender, content);
-                messages.push(WebMessage::new(message, timestamp as u64, id.to_string()));
-            } else {
-                println!("is not ok");
+                results.push(SearchResult {
+                    chat_id: row.read::<&str, _>("chat_id").to_string(),
+                    chat_title: row.read::<Option<&str>, _>("title").map(|x| x.to_string()),
+                    message_id: row.read::<&str, _>("id").to_string(),
+                    snippet: row.read::<&str, _>("snippet").to_string(),
+                    created_at: row.read::<i64, _>("datetime") as u64,
+                });
             }
         }
-        messages
+        results
     }
 
-    fn new_chat(&self, email: &str) -> String {
-        let id = uuid::Uuid::new_v4().to_string();
-        let query = "insert into Chats values (?, ?)";
+    // Generated titles never replace one the user already chose.
+    fn rename_chat(&self, email: &str, title: &ChatTitle, generated: bool) -> bool {
+        let query = if generated {
+            "update Chats set title = ? where email = ? and chat_id = ? and title is null"
+        } else {
+            "update Chats set title = ? where email = ? and chat_id = ?"
+        };
         let mut statement = self.connection.prepare(query).unwrap();
-        statement.bind_iter([(1, email), (2, id.as_str())]).unwrap();
+        statement
+            .bind_iter([
+                (1, title.title.as_str()),
+                (2, email),
+                (3, title.chat_id.as_str()),
+            ])
+            .unwrap();
         statement.iter().count();
-        id
+        self.connection.change_count() == 1
     }
 
-    fn validate_connection(&self, email: &str, password: &str) -> Option<String> {
-        let query = "select * from Users where email = ? and password = ?";
-        let mut statement = self.connection.prepare(query).unwrap();
-        let _ = statement.bind_iter([(1, email), (2, password)]);
-        if statement.into_iter().count() == 1 {
-            let query = "select * from Tokens where email = ?";
-            let mut statement = self.connection.prepare(query).unwrap();
-            statement.bind((1, email)).unwrap();
-            if statement.iter().count() == 1 {
-                for result in statement.into_iter() {
-                    if let Ok(row) = result {
-                        let expire = row.read::<i64, _>("expire");
-                        let now = std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs();
-                        if now as i64 <= expire {
-                            return Some(row.read::<&str, _>("token").to_string());
-                        } else {
-                            return Some(self.create_token(email));
-                        }
-                    }
-                }
-            } else {
-                Some(self.create_token(email));
-            }
-        }
-        None
+    // Moves the head of the chat to its newest message.
+    fn touch_chat(&self, email: &str, chat_id: &str, head: &str, now: u64) {
+        let query = "update Chats set updated_at = ?, head_id = ? where email = ? and chat_id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([
+                (1, now.to_string().as_str()),
+                (2, head),
+                (3, email),
+                (4, chat_id),
+            ])
+            .unwrap();
+        statement.iter().count();
     }
 
-    fn validate_token(&self, token: &str) -> Option<String> {
-        let query = "select * from Tokens where token = ?";
+    fn chat_head(&self, email: &str, chat_id: &str) -> Option<String> {
+        let query = "select head_id from Chats where email = ? and chat_id = ?";
         let mut statement = self.connection.prepare(query).unwrap();
-        statement.bind((1, token)).unwrap();
+        statement.bind_iter([(1, email), (2, chat_id)]).unwrap();
         for result in statement.into_iter() {
             if let Ok(row) = result {
-                let expire = row.read::<i64, _>("expire");
-                let now = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-                if now as i64 <= expire {
-                    return Some(row.read::<&str, _>("email").to_string());
-                } else {
-                    return None;
-                }
+                return row
+                    .read::<Option<&str>, _>("head_id")
+                    .map(|x| x.to_string());
             }
         }
         None
     }
 
-    fn create_token(&self, email: &str) -> String {
-        self.delete_tokens(email);
-        let mut seconds = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        seconds += 60 * 60 * 24;
-        let query = "insert into Tokens values (?, ?, ?)";
-        let token = uuid::Uuid::new_v4().to_string();
+    // Ids of the replies to `parent`, oldest first. `None` stands for the
+    // first messages of the chat.
+    fn children(&self, email: &str, chat_id: &str, parent: Option<&str>) -> Vec<String> {
+        let query = "select id from Messages where email = ? and chat_id = ? and parent_id is ? order by datetime, rowid";
         let mut statement = self.connection.prepare(query).unwrap();
         statement
-            .bind_iter([(1, token.as_str()), (2, email), (3, &seconds.to_string())])
+            .bind_iter([(1, Some(email)), (2, Some(chat_id)), (3, parent)])
             .unwrap();
-        statement.iter().count();
-        token
-    }
-
-    fn delete_tokens(&self, email: &str) {
-        let query = "delete from Tokens where email = ?";
-        let mut statement = self.connection.prepare(query).unwrap();
-        statement.bind((1, email)).unwrap();
-        statement.iter().count();
+        let mut children = Vec::new();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                children.push(row.read::<&str, _>("id").to_string());
+            }
+        }
+        children
     }
 
-    fn get_chats(&self, email: &str) -> Vec<String> {
-        let query = "select chat_id from Chats where email = ?";
+    // Adds `content` as a new sibling of the user message `message_id` and
+    // moves the head of the chat to it. The old branch stays as it was.
+    fn edit_message(
+        &self,
+        email: &str,
+        chat_id: &str,
+        message_id: &str,
+        new_id: &str,
+        content: &str,
+    ) -> Option<WebMessage> {
+        let query = "select parent_id from Messages where email = ? and chat_id = ? and id = ? and sender = 'user'";
         let mut statement = self.connection.prepare(query).unwrap();
-        statement.bind((1, email)).unwrap();
-        let mut chats = Vec::new();
+        statement
+            .bind_iter([(1, email), (2, chat_id), (3, message_id)])
+            .unwrap();
+        let mut parent = None;
         for result in statement.into_iter() {
             if let Ok(row) = result {
-                let chat_id = row.read::<&str, _>("chat_id");
-                chats.push(chat_id.to_string());
+                parent = Some(
+                    row.read::<Option<&str>, _>("parent_id")
+                        .map(|x| x.to_string()),
+                );
             }
         }
-        chats
+        let parent = parent?;
+        let origin = Origin {
+            parent: parent.as_deref(),
+            ..Default::default()
+        };
+        let timestamp = self.new_chat_message(email, "user", chat_id, content, new_id, origin);
+        let mut message =
+            WebMessage::new(Message::new("user", content), timestamp, new_id.to_string());
+        message.siblings = self.children(email, chat_id, parent.as_deref());
+        message.parent_id = parent;
+        Some(message)
     }
 
-    fn user_exists(&self, email: &str) -> bool {
-        let query = "select * from Users where email = ?";
+    // Makes the branch through `message_id` active, following the newest
+    // reply at every fork below it.
+    fn switch_branch(&self, email: &str, chat_id: &str, message_id: &str) -> bool {
+        let query = "select id from Messages where email = ? and chat_id = ? and id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([(1, email), (2, chat_id), (3, message_id)])
+            .unwrap();
+        if statement.iter().count() != 1 {
+            return false;
+        }
+        let mut head = message_id.to_string();
+        while let Some(child) = self.children(email, chat_id, Some(head.as_str())).pop() {
+            head = child;
+        }
+        self.set_head(email, chat_id, &head)
+    }
+
+    // Moves the head of the chat back to the last user message of the active
+    // branch, so the next answer becomes an alternative to the current ones.
+    // Returns that message, the ids of the answers it already has and the head
+    // before the rewind, to go back to if no new answer gets stored.
+    fn rewind(
+        &self,
+        email: &str,
+        chat_id: &str,
+    ) -> Option<(WebMessage, Vec<String>, Option<String>)> {
+        let head = self.chat_head(email, chat_id);
+        let message = self
+            .get_chat_messages(email, chat_id)
+            .into_iter()
+            .rev()
+            .find(|x| x.message.role.as_deref() == Some("user"))?;
+        if !self.set_head(email, chat_id, &message.id) {
+            return None;
+        }
+        let answers = self.children(email, chat_id, Some(message.id.as_str()));
+        Some((message, answers, head))
+    }
+
+    fn set_head(&self, email: &str, chat_id: &str, head: &str) -> bool {
+        let query = "update Chats set head_id = ? where email = ? and chat_id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([(1, head), (2, email), (3, chat_id)])
+            .unwrap();
+        statement.iter().count();
+        self.connection.change_count() == 1
+    }
+
+    fn get_chat_settings(&self, email: &str, chat_id: &str) -> Option<ChatSettings> {
+        let query = "select model, temperature, top_p, max_tokens, stop from Chats where email = ? and chat_id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind_iter([(1, email), (2, chat_id)]).unwrap();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                let stop = row
+                    .read::<Option<&str>, _>("stop")
+                    .and_then(|x| serde_json::from_str::<Vec<String>>(x).ok());
+                return Some(ChatSettings {
+                    chat_id: chat_id.to_string(),
+                    model: row.read::<Option<&str>, _>("model").map(|x| x.to_string()),
+                    sampling: Sampling {
+                        temperature: row.read::<Option<f64>, _>("temperature"),
+                        top_p: row.read::<Option<f64>, _>("top_p"),
+                        max_tokens: row.read::<Option<i64>, _>("max_tokens").map(|x| x as u64),
+                        stop,
+                    },
+                });
+            }
+        }
+        None
+    }
+
+    fn set_chat_settings(&self, email: &str, settings: &ChatSettings) -> bool {
+        let query = "update Chats set model = ?, temperature = ?, top_p = ?, max_tokens = ?, stop = ? where email = ? and chat_id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        let sampling = &settings.sampling;
+        let stop = sampling
+            .stop
+            .as_ref()
+            .map(|x| serde_json::json!(x).to_string());
+        statement.bind((1, settings.model.as_deref())).unwrap();
+        statement.bind((2, sampling.temperature)).unwrap();
+        statement.bind((3, sampling.top_p)).unwrap();
+        statement
+            .bind((4, sampling.max_tokens.map(|x| x as i64)))
+            .unwrap();
+        statement.bind((5, stop.as_deref())).unwrap();
+        statement.bind((6, email)).unwrap();
+        statement.bind((7, settings.chat_id.as_str())).unwrap();
+        statement.iter().count();
+        self.connection.change_count() == 1
+    }
+
+    fn get_personas(&self, email: &str) -> Vec<Persona> {
+        let query = "select name, prompt from Personas where email = ? order by name";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, email)).unwrap();
+        let mut personas = Vec::new();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                let name = row.read::<&str, _>("name");
+                let prompt = row.read::<&str, _>("prompt");
+                personas.push(Persona::new(name.to_string(), prompt.to_string()));
+            }
+        }
+        personas
+    }
+
+    fn save_persona(&self, email: &str, persona: &Persona) {
+        let query = "insert or replace into Personas (email, name, prompt) values (?, ?, ?)";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([
+                (1, email),
+                (2, persona.name.as_str()),
+                (3, persona.prompt.as_str()),
+            ])
+            .unwrap();
+        statement.iter().count();
+    }
+
+    fn delete_persona(&self, email: &str, name: &str) {
+        let query = "delete from Personas where email = ? and name = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind_iter([(1, email), (2, name)]).unwrap();
+        statement.iter().count();
+    }
+
+    fn get_summary(&self, email: &str, chat_id: &str) -> Option<Summary> {
+        let query = "select content, until_id from Summaries where email = ? and chat_id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind_iter([(1, email), (2, chat_id)]).unwrap();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                let content = row.read::<&str, _>("content");
+                let until = row.read::<&str, _>("until_id");
+                return Some(Summary::new(content.to_string(), until.to_string()));
+            }
+        }
+        None
+    }
+
+    fn save_summary(&self, email: &str, chat_id: &str, summary: &Summary) {
+        let query = "insert or replace into Summaries (email, chat_id, content, until_id) values (?, ?, ?, ?)";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([
+                (1, email),
+                (2, chat_id),
+                (3, summary.content.as_str()),
+                (4, summary.until.as_str()),
+            ])
+            .unwrap();
+        statement.iter().count();
+    }
+
+    fn delete_summary(&self, email: &str, chat_id: &str) {
+        let query = "delete from Summaries where email = ? and chat_id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind_iter([(1, email), (2, chat_id)]).unwrap();
+        statement.iter().count();
+    }
+
+    fn delete_chat(&self, email: &str, chat_id: &str) {
+        let query = "delete from Chats where email = ? and chat_id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind_iter([(1, email), (2, chat_id)]).unwrap();
+        statement.iter().count();
+    }
+
+    fn new_chat_message(
+        &self,
+        email: &str,
+        sender: &str,
+        chat_id: &str,
+        content: &str,
+        message_id: &str,
+        origin: Origin,
+    ) -> u64 {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let query = "insert into Messages (email, chat_id, sender, content, datetime, id, truncated, model, parent_id) values (?, ?, ?, ?, ?, ?, ?, ?, ?)";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([
+                (1, Some(email)),
+                (2, Some(chat_id)),
+                (3, Some(sender)),
+                (4, Some(content)),
+                (5, Some(&now.to_string())),
+                (6, Some(message_id)),
+                (7, Some(if origin.truncated { "1" } else { "0" })),
+                (8, origin.model),
+                (9, origin.parent),
+            ])
+            .expect("coulndt fit now");
+        println!("{}", statement.iter().count());
+        self.touch_chat(email, chat_id, message_id, now);
+        now
+    }
+
+    // The active branch of a chat, oldest first: its head and every ancestor.
+    fn get_chat_messages(&self, email: &str, chat_id: &str) -> Vec<WebMessage> {
+        let query = "with recursive branch (id, depth) as (select head_id, 0 from Chats where email = :email and chat_id = :chat_id union all select Messages.parent_id, branch.depth + 1 from Messages join branch on Messages.id = branch.id where Messages.email = :email and Messages.parent_id is not null) select Messages.* from branch join Messages on Messages.id = branch.id where Messages.email = :email order by branch.depth desc";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((":email", email)).unwrap();
+        statement.bind((":chat_id", chat_id)).unwrap();
+        let mut messages = Vec::new();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                messages.push(read_message(&row));
+            } else {
+                println!("is not ok");
+            }
+        }
+        let forks = self.forks(email, chat_id);
+        for message in messages.iter_mut() {
+            if let Some(siblings) = forks.get(&message.parent_id) {
+                message.siblings = siblings.clone();
+            }
+        }
+        messages
+    }
+
+    // Replies of every message with more than one, keyed by the parent.
+    fn forks(&self, email: &str, chat_id: &str) -> HashMap<Option<String>, Vec<String>> {
+        let query = "select id, parent_id from Messages where email = ? and chat_id = ? order by datetime, rowid";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind_iter([(1, email), (2, chat_id)]).unwrap();
+        let mut children: HashMap<Option<String>, Vec<String>> = HashMap::new();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                let parent = row
+                    .read::<Option<&str>, _>("parent_id")
+                    .map(|x| x.to_string());
+                let id = row.read::<&str, _>("id").to_string();
+                children.entry(parent).or_default().push(id);
+            }
+        }
+        children.retain(|_, x| x.len() > 1);
+        children
+    }
+
+    // Pages walk the active branch. The cursor is the id of a message on it.
+    fn get_message_page(
+        &self,
+        email: &str,
+        chat_id: &str,
+        page: &PageRequest,
+    ) -> Option<MessagePage> {
+        if !self.chat_exists(email, chat_id) {
+            return None;
+        }
+        let mut messages = self.get_chat_messages(email, chat_id);
+        let limit = page.limit as usize;
+        let (start, end) = match page.cursor {
+            Some(ref cursor) => {
+                let position = messages.iter().position(|x| &x.id == cursor)?;
+                match page.direction {
+                    Direction::Before => (0, position),
+                    Direction::After => (position + 1, messages.len()),
+                }
+            }
+            None => (0, messages.len()),
+        };
+        let (start, end, has_more) = match page.direction {
+            Direction::Before => {
+                let first = end.saturating_sub(limit).max(start);
+                (first, end, first > start)
+            }
+            Direction::After => {
+                let last = (start + limit).min(end);
+                (start, last, last < end)
+            }
+        };
+        messages.truncate(end);
+        messages.drain(..start);
+        Some(MessagePage {
+            chat_id: chat_id.to_string(),
+            messages,
+            has_more,
+        })
+    }
+
+    fn chat_exists(&self, email: &str, chat_id: &str) -> bool {
+        let query = "select chat_id from Chats where email = ? and chat_id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind_iter([(1, email), (2, chat_id)]).unwrap();
+        statement.iter().count() == 1
+    }
+
+    fn message_exists(&self, email: &str, message_id: &str) -> bool {
+        let query = "select id from Messages where email = ? and id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind_iter([(1, email), (2, message_id)]).unwrap();
+        statement.iter().count() == 1
+    }
+
+    fn new_chat(&self, email: &str) -> String {
+        let id = uuid::Uuid::new_v4().to_string();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string();
+        let query =
+            "insert into Chats (email, chat_id, created_at, updated_at) values (?, ?, ?, ?)";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([(1, email), (2, id.as_str()), (3, &now), (4, &now)])
+            .unwrap();
+        statement.iter().count();
+        id
+    }
+
+    fn get_password_hash(&self, email: &str) -> Option<String> {
+        let query = "select password from Users where email = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, email)).unwrap();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                return Some(row.read::<&str, _>("password").to_string());
+            }
+        }
+        None
+    }
+
+    fn set_password_hash(&self, email: &str, password_hash: &str) {
+        let query = "update Users set password = ? where email = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([(1, password_hash), (2, email)])
+            .unwrap();
+        statement.iter().count();
+    }
+
+    // The password has already been checked, see `NetworkMessage::LoginRequest`.
+    // Every login starts a session of its own.
+    fn validate_connection(&self, email: &str, device: Option<&str>) -> Option<Credentials> {
+        if !self.user_exists(email) {
+            return None;
+        }
+        Some(self.create_token(email, device))
+    }
+
+    // Binds the connection to the session of `token`, or to the API key it
+    // is. Its later requests act as that user, for as long as either lasts.
+    // With `pushes` it also gets the changes other devices make.
+    fn bind(&mut self, id: &str, token: &str, pushes: bool) -> Result<String, ValidationError> {
+        let (email, credential) = match self.session_id(token) {
+            Some(session_id) => (
+                self.check_session(&session_id)?,
+                Credential::Session(session_id),
+            ),
+            None => {
+                let key_id = self
+                    .api_key_id(token)
+                    .ok_or(ValidationError::InvalidCredentials)?;
+                (self.check_api_key(&key_id)?, Credential::ApiKey(key_id))
+            }
+        };
+        self.unbind(id);
+        self.connections.insert(id.to_string(), credential);
+        if !pushes {
+            return Ok(email);
+        }
+        if let Some(sender) = self.push_senders.get(id) {
+            let entry = self.email_senders.entry(email.to_string()).or_default();
+            entry.insert(id.to_string(), sender.clone());
+        }
+        Ok(email)
+    }
+
+    fn unbind(&mut self, id: &str) {
+        self.connections.remove(id);
+        for senders in self.email_senders.values_mut() {
+            senders.remove(id);
+        }
+        self.email_senders.retain(|_, senders| !senders.is_empty());
+    }
+
+    // Closes the connections bound to any of `credentials`, ids of sessions or
+    // API keys which no longer exist.
+    fn disconnect(&mut self, credentials: &[String]) {
+        let ids: Vec<String> = self
+            .connections
+            .iter()
+            .filter(|(_, credential)| match credential {
+                Credential::Session(id) | Credential::ApiKey(id) => credentials.contains(id),
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in ids {
+            if let Some(sender) = self.push_senders.get(&id) {
+                let _ = sender.send(DatabaseMessage::Revoked);
+            }
+            self.unbind(&id);
+        }
+    }
+
+    // Connections bound to an API key only get what its scopes allow.
+    fn permits(&self, id: &str, requirement: &Requirement) -> bool {
+        let Some(Credential::ApiKey(key_id)) = self.connections.get(id) else {
+            // Sessions hold every scope but `completions`, which keeps the
+            // `/v1` endpoints to API keys.
+            return !matches!(requirement, Requirement::Scope(Scope::Completions));
+        };
+        match requirement {
+            Requirement::Scope(scope) => self.api_key_scopes(key_id).contains(scope),
+            Requirement::Session => false,
+        }
+    }
+
+    // The user the connection authenticated as.
+    fn authorize(&self, id: &str) -> Option<String> {
+        self.check_connection(id).ok()
+    }
+
+    // The user the connection authenticated as, provided `chat_id` is theirs.
+    fn authorize_chat(&self, id: &str, chat_id: &str) -> Option<String> {
+        self.authorize(id)
+            .filter(|email| self.chat_exists(email, chat_id))
+    }
+
+    // The user the connection authenticated as, provided `message_id` is theirs.
+    fn authorize_message(&self, id: &str, message_id: &str) -> Option<String> {
+        self.authorize(id)
+            .filter(|email| self.message_exists(email, message_id))
+    }
+
+    fn check_connection(&self, id: &str) -> Result<String, ValidationError> {
+        match self.connections.get(id) {
+            Some(Credential::Session(session_id)) => self.check_session(session_id),
+            Some(Credential::ApiKey(key_id)) => self.check_api_key(key_id),
+            None => Err(ValidationError::InvalidCredentials),
+        }
+    }
+
+    fn session_id(&self, token: &str) -> Option<String> {
+        let query = "select id from Sessions where token = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, token)).unwrap();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                return Some(row.read::<&str, _>("id").to_string());
+            }
+        }
+        None
+    }
+
+    fn check_session(&self, session_id: &str) -> Result<String, ValidationError> {
+        let query = "select * from Sessions where id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, session_id)).unwrap();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                let expire = row.read::<i64, _>("expire");
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                if now as i64 <= expire {
+                    self.touch_session(session_id, now);
+                    return Ok(row.read::<&str, _>("email").to_string());
+                } else {
+                    return Err(ValidationError::TokenExpired);
+                }
+            }
+        }
+        Err(ValidationError::InvalidCredentials)
+    }
+
+    // The reply to a request that failed, telling clients when renewing the
+    // access token would let it through.
+    fn auth_error(&self, id: &str) -> DatabaseMessage {
+        match self.check_connection(id) {
+            Err(ValidationError::TokenExpired) => DatabaseMessage::TokenExpired,
+            _ => DatabaseMessage::Err,
+        }
+    }
+
+    fn touch_session(&self, session_id: &str, now: u64) {
+        let query = "update Sessions set last_seen = ? where id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([(1, now.to_string().as_str()), (2, session_id)])
+            .unwrap();
+        statement.iter().count();
+    }
+
+    // Issues a new API key for `email`. Only its hash is stored, so this is
+    // the one time the key itself is known.
+    fn create_api_key(&self, email: &str, request: &CreateApiKey) -> NewApiKey {
+        let key = format!(
+            "sk-{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut scopes = Vec::new();
+        for scope in &request.scopes {
+            if !scopes.contains(scope) {
+                scopes.push(*scope);
+            }
+        }
+        let info = ApiKey {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: request.name.trim().to_string(),
+            scopes,
+            created_at: now,
+            last_used: None,
+            expires_at: request.expires_at,
+        };
+        let query = "insert into ApiKeys (id, email, key_hash, created_at, name, scopes, expires_at) values (?, ?, ?, ?, ?, ?, ?)";
+        let key_hash = hash_api_key(&key);
+        let scopes = serde_json::to_string(&info.scopes).unwrap();
+        let created_at = now.to_string();
+        let expires_at = info.expires_at.map(|x| x.to_string());
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([
+                (1, Some(info.id.as_str())),
+                (2, Some(email)),
+                (3, Some(key_hash.as_str())),
+                (4, Some(created_at.as_str())),
+                (5, Some(info.name.as_str())),
+                (6, Some(scopes.as_str())),
+                (7, expires_at.as_deref()),
+            ])
+            .unwrap();
+        statement.iter().count();
+        NewApiKey { key, info }
+    }
+
+    fn get_api_keys(&self, email: &str) -> Vec<ApiKey> {
+        let query = "select * from ApiKeys where email = ? order by created_at desc";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, email)).unwrap();
+        let mut keys = Vec::new();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                keys.push(ApiKey {
+                    id: row.read::<&str, _>("id").to_string(),
+                    name: row.read::<&str, _>("name").to_string(),
+                    scopes: serde_json::from_str(row.read::<&str, _>("scopes")).unwrap_or_default(),
+                    created_at: row.read::<i64, _>("created_at") as u64,
+                    last_used: row.read::<Option<i64>, _>("last_used").map(|x| x as u64),
+                    expires_at: row.read::<Option<i64>, _>("expires_at").map(|x| x as u64),
+                });
+            }
+        }
+        keys
+    }
+
+    fn revoke_api_key(&self, email: &str, key_id: &str) -> bool {
+        let query = "delete from ApiKeys where email = ? and id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind_iter([(1, email), (2, key_id)]).unwrap();
+        statement.iter().count();
+        self.connection.change_count() == 1
+    }
+
+    fn api_key_scopes(&self, key_id: &str) -> Vec<Scope> {
+        let query = "select scopes from ApiKeys where id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, key_id)).unwrap();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                return serde_json::from_str(row.read::<&str, _>("scopes")).unwrap_or_default();
+            }
+        }
+        Vec::new()
+    }
+
+    fn api_key_id(&self, key: &str) -> Option<String> {
+        let query = "select id from ApiKeys where key_hash = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, hash_api_key(key).as_str())).unwrap();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                return Some(row.read::<&str, _>("id").to_string());
+            }
+        }
+        None
+    }
+
+    // Keys cannot be refreshed, so an expired one is simply invalid.
+    fn check_api_key(&self, key_id: &str) -> Result<String, ValidationError> {
+        let query = "select email, expires_at from ApiKeys where id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, key_id)).unwrap();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                let expires_at = row.read::<Option<i64>, _>("expires_at");
+                if expires_at.is_some_and(|x| x < now as i64) {
+                    return Err(ValidationError::InvalidCredentials);
+                }
+                self.touch_api_key(key_id, now);
+                return Ok(row.read::<&str, _>("email").to_string());
+            }
+        }
+        Err(ValidationError::InvalidCredentials)
+    }
+
+    fn touch_api_key(&self, key_id: &str, now: u64) {
+        let query = "update ApiKeys set last_used = ? where id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([(1, now.to_string().as_str()), (2, key_id)])
+            .unwrap();
+        statement.iter().count();
+    }
+
+    fn create_token(&self, email: &str, device: Option<&str>) -> Credentials {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.delete_expired_tokens(email, now);
+        let query = "insert into Sessions (id, email, token, device, created_at, last_seen, expire, refresh_expire) values (?, ?, ?, ?, ?, ?, ?, ?)";
+        let id = uuid::Uuid::new_v4().to_string();
+        let token = uuid::Uuid::new_v4().to_string();
+        let expire = now + ACCESS_TOKEN_LIFETIME;
+        let refresh_expire = now + REFRESH_TOKEN_LIFETIME;
+        let now = now.to_string();
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([
+                (1, Some(id.as_str())),
+                (2, Some(email)),
+                (3, Some(token.as_str())),
+                (4, device),
+                (5, Some(now.as_str())),
+                (6, Some(now.as_str())),
+                (7, Some(expire.to_string().as_str())),
+                (8, Some(refresh_expire.to_string().as_str())),
+            ])
+            .unwrap();
+        statement.iter().count();
+        Credentials {
+            token,
+            refresh_token: self.create_refresh_token(&id),
+            expires_at: expire,
+        }
+    }
+
+    fn create_refresh_token(&self, session_id: &str) -> String {
+        let query = "insert into RefreshTokens (token, session_id) values (?, ?)";
+        let token = uuid::Uuid::new_v4().to_string();
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([(1, token.as_str()), (2, session_id)])
+            .unwrap();
+        statement.iter().count();
+        token
+    }
+
+    // Trades a refresh token for a new access token and a new refresh token.
+    // A refresh token that was already used means it leaked, so the whole
+    // session is revoked.
+    fn refresh(&mut self, refresh_token: &str) -> Option<Credentials> {
+        let query = "select RefreshTokens.used as used, Sessions.id as id, Sessions.refresh_expire as refresh_expire from RefreshTokens join Sessions on Sessions.id = RefreshTokens.session_id where RefreshTokens.token = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, refresh_token)).unwrap();
+        let mut found = None;
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                found = Some((
+                    row.read::<i64, _>("used") != 0,
+                    row.read::<&str, _>("id").to_string(),
+                    row.read::<i64, _>("refresh_expire"),
+                ));
+            }
+        }
+        let (used, session_id, refresh_expire) = found?;
+        if used {
+            println!("refresh token reused, revoking its session");
+            self.delete_session(&session_id);
+            self.disconnect(&[session_id]);
+            return None;
+        }
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if (now as i64) > refresh_expire {
+            return None;
+        }
+        let query = "update RefreshTokens set used = 1 where token = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, refresh_token)).unwrap();
+        statement.iter().count();
+        let token = uuid::Uuid::new_v4().to_string();
+        let expire = now + ACCESS_TOKEN_LIFETIME;
+        let query = "update Sessions set token = ?, expire = ?, refresh_expire = ?, last_seen = ? where id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([
+                (1, token.as_str()),
+                (2, expire.to_string().as_str()),
+                (3, (now + REFRESH_TOKEN_LIFETIME).to_string().as_str()),
+                (4, now.to_string().as_str()),
+                (5, session_id.as_str()),
+            ])
+            .unwrap();
+        statement.iter().count();
+        Some(Credentials {
+            token,
+            refresh_token: self.create_refresh_token(&session_id),
+            expires_at: expire,
+        })
+    }
+
+    fn delete_session(&self, session_id: &str) {
+        let query = "delete from RefreshTokens where session_id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, session_id)).unwrap();
+        statement.iter().count();
+        let query = "delete from Sessions where id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, session_id)).unwrap();
+        statement.iter().count();
+    }
+
+    fn delete_expired_tokens(&self, email: &str, now: u64) {
+        let query = "delete from RefreshTokens where session_id in (select id from Sessions where email = ? and refresh_expire < ?)";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([(1, email), (2, now.to_string().as_str())])
+            .unwrap();
+        statement.iter().count();
+        let query = "delete from Sessions where email = ? and refresh_expire < ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement
+            .bind_iter([(1, email), (2, now.to_string().as_str())])
+            .unwrap();
+        statement.iter().count();
+    }
+
+    fn delete_tokens(&self, email: &str) {
+        let query = "delete from RefreshTokens where session_id in (select id from Sessions where email = ?)";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, email)).unwrap();
+        statement.iter().count();
+        let query = "delete from Sessions where email = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, email)).unwrap();
+        statement.iter().count();
+    }
+
+    // `id` is the connection asking, whose session is flagged as current.
+    fn get_sessions(&self, email: &str, id: &str) -> Vec<Session> {
+        let current = match self.connections.get(id) {
+            Some(Credential::Session(session_id)) => Some(session_id),
+            _ => None,
+        };
+        let query = "select * from Sessions where email = ? order by last_seen desc";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, email)).unwrap();
+        let mut sessions = Vec::new();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                sessions.push(Session {
+                    id: row.read::<&str, _>("id").to_string(),
+                    device: row.read::<Option<&str>, _>("device").map(|x| x.to_string()),
+                    created_at: row.read::<i64, _>("created_at") as u64,
+                    last_seen: row.read::<i64, _>("last_seen") as u64,
+                    expires_at: row.read::<i64, _>("refresh_expire") as u64,
+                    current: current.is_some_and(|x| x == row.read::<&str, _>("id")),
+                });
+            }
+        }
+        sessions
+    }
+
+    fn revoke_session(&self, email: &str, session_id: &str) -> bool {
+        let query = "select id from Sessions where email = ? and id = ?";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind_iter([(1, email), (2, session_id)]).unwrap();
+        if statement.iter().count() != 1 {
+            return false;
+        }
+        self.delete_session(session_id);
+        true
+    }
+
+    fn get_chats(&self, email: &str) -> Vec<ChatEntry> {
+        let query = "select chat_id, title, created_at, updated_at, (select count(*) from Messages where Messages.email = Chats.email and Messages.chat_id = Chats.chat_id) as message_count from Chats where email = ? order by updated_at desc";
+        let mut statement = self.connection.prepare(query).unwrap();
+        statement.bind((1, email)).unwrap();
+        let mut chats = Vec::new();
+        for result in statement.into_iter() {
+            if let Ok(row) = result {
+                chats.push(ChatEntry {
+                    chat_id: row.read::<&str, _>("chat_id").to_string(),
+                    title: row.read::<Option<&str>, _>("title").map(|x| x.to_string()),
+                    created_at: row.read::<i64, _>("created_at") as u64,
+                    updated_at: row.read::<i64, _>("updated_at") as u64,
+                    message_count: row.read::<i64, _>("message_count") as u64,
+                });
+            }
+        }
+        chats
+    }
+
+    fn user_exists(&self, email: &str) -> bool {
+        let query = "select * from Users where email = ?";
         let mut statement = self.connection.prepare(query).unwrap();
         statement.bind((1, email)).unwrap();
         statement.iter().count() == 1
     }
 
-    fn register_user(&self, name: &str, email: &str, password: &str) {
-        let mut sha = sha2::Sha256::new();
-        sha.update(password);
-        let password_hash = sha.finalize();
-        let hash = hex::encode(password_hash);
+    fn register_user(&self, name: &str, email: &str, password_hash: &str) {
         let query = "insert into Users values (?, ?)";
         let mut statement = self.connection.prepare(query).unwrap();
-        statement.bind_iter([(1, email), (2, &hash)]).unwrap();
+        statement
+            .bind_iter([(1, email), (2, password_hash)])
+            .unwrap();
         statement.iter().count();
         let query = "insert into UserInfo values (?, ?)";
         let mut statement = self.connection.prepare(query).unwrap();
@@ -422,3 +1695,515 @@ impl DbConnection {
         statement.iter().count();
     }
 }
+
+fn read_message(row: &sqlite::Row) -> WebMessage {
+    let sender = row.read::<&str, _>("sender");
+    let content = row.read::<&str, _>("content");
+    let timestamp = row.read::<i64, _>("datetime");
+    let id = row.read::<&str, _>("id");
+    let message = Message::new(sender, content);
+    let mut message = WebMessage::new(message, timestamp as u64, id.to_string());
+    message.truncated = row.read::<i64, _>("truncated") != 0;
+    message.model = row.read::<Option<&str>, _>("model").map(|x| x.to_string());
+    message.parent_id = row
+        .read::<Option<&str>, _>("parent_id")
+        .map(|x| x.to_string());
+    message
+}
+
+// Turns free text into an FTS5 query matching every word, the last one as a
+// prefix, so user input can never be parsed as query syntax.
+fn fts_query(text: &str) -> Option<String> {
+    let terms = text
+        .split_whitespace()
+        .map(|x| format!("\"{}\"", x.replace('"', "\"\"")))
+        .collect::<Vec<String>>();
+    if terms.is_empty() {
+        return None;
+    }
+    Some(format!("{}*", terms.join(" ")))
+}
+
+// What `message` takes from connections that authenticated with an API key,
+// along with the id of the connection it comes from.
+fn requirement(message: &NetworkMessage) -> Option<(&str, Requirement)> {
+    use NetworkMessage::*;
+    let (id, requirement) = match message {
+        ChatRequest(id, _)
+        | GetMessagePage(id, ..)
+        | GetChats(id)
+        | GetMessage(id, _)
+        | GetAudioPath(id, _)
+        | GetSummary(id, _)
+        | GetSystemPrompt(id, _)
+        | GetPersonas(id)
+        | GetChatSettings(id, _)
+        | Search(id, ..) => (id, Requirement::Scope(Scope::Read)),
+        NewChat(id)
+        | NewMessage(id, ..)
+        | DeleteChat(id, _)
+        | RecordAudioPath(id, ..)
+        | SaveSummary(id, ..)
+        | SetSystemPrompt(id, _)
+        | SavePersona(id, _)
+        | DeletePersona(id, _)
+        | SetChatSettings(id, _)
+        | RenameChat(id, ..)
+        | EditMessage(id, ..)
+        | SwitchBranch(id, ..)
+        | Regenerate(id, _) => (id, Requirement::Scope(Scope::Write)),
+        CheckScope(id, scope, _) => (id, Requirement::Scope(*scope)),
+        GetSessions(id)
+        | RevokeSession(id, _)
+        | LogoutAll(id)
+        | CreateApiKey(id, _)
+        | GetApiKeys(id)
+        | RevokeApiKey(id, _) => (id, Requirement::Session),
+        LoginRequest(..) | GetPasswordHash(..) | Refresh(..) | Authenticate(..) | Authorize(..)
+        | RegisterUser(..) | Disconnect(_) => return None,
+    };
+    Some((id.as_str(), requirement))
+}
+
+// API keys are random enough that a plain hash keeps them safe at rest, and
+// unlike a salted one it can be looked up.
+fn hash_api_key(key: &str) -> String {
+    let mut sha = sha2::Sha256::new();
+    sha.update(key.as_bytes());
+    hex::encode(sha.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A database in memory, driven through the same channels the server uses.
+    struct Harness {
+        db: DbConnection,
+        network: Sender<NetworkMessage>,
+        connections: Sender<String>,
+        receivers: Receiver<Channels>,
+        // The push channel of every connection, by id.
+        pushes: HashMap<String, Receiver<DatabaseMessage>>,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            let (network, receiver) = channel();
+            let (connections, nreceiver) = channel();
+            let (receiver_sender, receivers) = channel();
+            let connection = Connection::open(":memory:").unwrap();
+            Self {
+                db: DbConnection::with_connection(connection, receiver, nreceiver, receiver_sender),
+                network,
+                connections,
+                receivers,
+                pushes: HashMap::new(),
+            }
+        }
+
+        fn connect(&mut self) -> (String, Receiver<DatabaseMessage>) {
+            let id = uuid::Uuid::new_v4().to_string();
+            self.connections.send(id.clone()).unwrap();
+            self.db.receive_new_connections();
+            let channels = self.receivers.try_recv().unwrap();
+            self.pushes.insert(id.clone(), channels.pushes);
+            (id, channels.replies)
+        }
+
+        fn push(&self, id: &str) -> Option<DatabaseMessage> {
+            self.pushes[id].try_recv().ok()
+        }
+
+        // A connection signed in as a new user.
+        fn user(&mut self, email: &str) -> (String, Receiver<DatabaseMessage>) {
+            let (id, receiver) = self.connect();
+            let message = NetworkMessage::RegisterUser(
+                id.clone(),
+                email.to_string(),
+                email.to_string(),
+                "hash".to_string(),
+                None,
+            );
+            let response = self.request(&receiver, message);
+            assert!(matches!(response, DatabaseMessage::Token(_)));
+            (id, receiver)
+        }
+
+        fn request(
+            &mut self,
+            receiver: &Receiver<DatabaseMessage>,
+            message: NetworkMessage,
+        ) -> DatabaseMessage {
+            self.network.send(message).unwrap();
+            self.db.receive_messages();
+            receiver.try_recv().unwrap()
+        }
+
+        fn new_chat(&mut self, id: &str, receiver: &Receiver<DatabaseMessage>) -> String {
+            match self.request(receiver, NetworkMessage::NewChat(id.to_string())) {
+                DatabaseMessage::NewChat(chat_id) => chat_id,
+                response => panic!("unexpected {response:?}"),
+            }
+        }
+
+        fn new_message(
+            &mut self,
+            id: &str,
+            receiver: &Receiver<DatabaseMessage>,
+            chat_id: &str,
+        ) -> DatabaseMessage {
+            let message = NetworkMessage::NewMessage(
+                id.to_string(),
+                "user".to_string(),
+                chat_id.to_string(),
+                "secret".to_string(),
+                uuid::Uuid::new_v4().to_string(),
+                false,
+                None,
+            );
+            self.request(receiver, message)
+        }
+
+        // A new chat with one message, returning the ids of both.
+        fn first_message(
+            &mut self,
+            id: &str,
+            receiver: &Receiver<DatabaseMessage>,
+        ) -> (String, String) {
+            let chat_id = self.new_chat(id, receiver);
+            self.new_message(id, receiver, &chat_id);
+            let message = NetworkMessage::ChatRequest(id.to_string(), chat_id.clone());
+            match self.request(receiver, message) {
+                DatabaseMessage::Messages(_, messages) => (chat_id, messages[0].id.clone()),
+                response => panic!("unexpected {response:?}"),
+            }
+        }
+    }
+
+    // Creates an API key for the user of connection `id`, expiring at
+    // `expires_at`, and returns the key.
+    fn api_key(
+        harness: &mut Harness,
+        id: &str,
+        receiver: &Receiver<DatabaseMessage>,
+        scopes: Vec<Scope>,
+        expires_at: Option<u64>,
+    ) -> NewApiKey {
+        let request = CreateApiKey {
+            token: String::new(),
+            name: "script".to_string(),
+            scopes,
+            expires_at,
+        };
+        let message = NetworkMessage::CreateApiKey(id.to_string(), request);
+        match harness.request(receiver, message) {
+            DatabaseMessage::ApiKeyCreated(key) => key,
+            response => panic!("unexpected {response:?}"),
+        }
+    }
+
+    #[test]
+    fn api_keys_are_limited_to_their_scopes() {
+        let mut harness = Harness::new();
+        let (alice, alice_receiver) = harness.user("alice@example.com");
+        let key = api_key(
+            &mut harness,
+            &alice,
+            &alice_receiver,
+            vec![Scope::Read],
+            None,
+        );
+        let (script, receiver) = harness.connect();
+        let message = NetworkMessage::Authenticate(script.clone(), key.key);
+        let response = harness.request(&receiver, message);
+        assert!(matches!(response, DatabaseMessage::Email(_)));
+
+        let response = harness.request(&receiver, NetworkMessage::GetChats(script.clone()));
+        assert!(matches!(response, DatabaseMessage::Chats(_)));
+        let requests = [
+            NetworkMessage::NewChat(script.clone()),
+            NetworkMessage::CheckScope(script.clone(), Scope::Completions, None),
+            NetworkMessage::GetApiKeys(script.clone()),
+            NetworkMessage::GetSessions(script.clone()),
+        ];
+        for request in requests {
+            let response = harness.request(&receiver, request);
+            assert!(matches!(response, DatabaseMessage::MissingScope));
+        }
+
+        match harness.request(&alice_receiver, NetworkMessage::GetApiKeys(alice.clone())) {
+            DatabaseMessage::ApiKeys(keys) => {
+                assert_eq!(keys.len(), 1);
+                assert_eq!(keys[0].scopes, vec![Scope::Read]);
+                assert!(keys[0].last_used.is_some());
+            }
+            response => panic!("unexpected {response:?}"),
+        }
+    }
+
+    #[test]
+    fn only_api_keys_hold_the_completions_scope() {
+        let mut harness = Harness::new();
+        let (alice, alice_receiver) = harness.user("alice@example.com");
+        let message = NetworkMessage::CheckScope(alice.clone(), Scope::Write, None);
+        let response = harness.request(&alice_receiver, message);
+        assert!(matches!(response, DatabaseMessage::Ok));
+        let message = NetworkMessage::CheckScope(alice.clone(), Scope::Completions, None);
+        let response = harness.request(&alice_receiver, message);
+        assert!(matches!(response, DatabaseMessage::MissingScope));
+
+        let key = api_key(
+            &mut harness,
+            &alice,
+            &alice_receiver,
+            vec![Scope::Completions],
+            None,
+        );
+        let (script, receiver) = harness.connect();
+        let message = NetworkMessage::Authorize(script.clone(), key.key);
+        harness.request(&receiver, message);
+        let message = NetworkMessage::CheckScope(script, Scope::Completions, None);
+        let response = harness.request(&receiver, message);
+        assert!(matches!(response, DatabaseMessage::Ok));
+    }
+
+    #[test]
+    fn revoked_and_expired_api_keys_are_rejected() {
+        let mut harness = Harness::new();
+        let (alice, alice_receiver) = harness.user("alice@example.com");
+        let key = api_key(
+            &mut harness,
+            &alice,
+            &alice_receiver,
+            vec![Scope::Read],
+            None,
+        );
+        let (script, receiver) = harness.connect();
+        let message = NetworkMessage::Authenticate(script.clone(), key.key.clone());
+        harness.request(&receiver, message);
+
+        let message = NetworkMessage::RevokeApiKey(alice.clone(), key.info.id);
+        match harness.request(&alice_receiver, message) {
+            DatabaseMessage::ApiKeys(keys) => assert!(keys.is_empty()),
+            response => panic!("unexpected {response:?}"),
+        }
+        assert!(receiver.try_recv().is_err());
+        assert!(matches!(
+            harness.push(&script),
+            Some(DatabaseMessage::Revoked)
+        ));
+        let response = harness.request(&receiver, NetworkMessage::GetChats(script.clone()));
+        assert!(matches!(response, DatabaseMessage::Err));
+        let message = NetworkMessage::Authenticate(script.clone(), key.key);
+        let response = harness.request(&receiver, message);
+        assert!(matches!(response, DatabaseMessage::Err));
+
+        let key = api_key(
+            &mut harness,
+            &alice,
+            &alice_receiver,
+            vec![Scope::Read],
+            Some(1),
+        );
+        let message = NetworkMessage::Authenticate(script, key.key);
+        let response = harness.request(&receiver, message);
+        assert!(matches!(response, DatabaseMessage::Err));
+    }
+
+    #[test]
+    fn authorized_connections_get_no_pushes() {
+        let mut harness = Harness::new();
+        let (alice, alice_receiver) = harness.user("alice@example.com");
+        let key = api_key(
+            &mut harness,
+            &alice,
+            &alice_receiver,
+            vec![Scope::Read],
+            None,
+        );
+        let (device, device_receiver) = harness.connect();
+        let message = NetworkMessage::Authenticate(device.clone(), key.key.clone());
+        harness.request(&device_receiver, message);
+        let (script, receiver) = harness.connect();
+        let message = NetworkMessage::Authorize(script.clone(), key.key);
+        let response = harness.request(&receiver, message);
+        assert!(matches!(response, DatabaseMessage::Email(_)));
+
+        harness.new_chat(&alice, &alice_receiver);
+        assert!(matches!(
+            harness.push(&device),
+            Some(DatabaseMessage::NewChat(_))
+        ));
+        assert!(device_receiver.try_recv().is_err());
+        assert!(harness.push(&script).is_none());
+    }
+
+    #[test]
+    fn search_input_is_quoted_word_by_word() {
+        assert_eq!(fts_query("hello world").unwrap(), r#""hello" "world"*"#);
+        assert_eq!(fts_query(r#"say "hi""#).unwrap(), r#""say" """hi"""*"#);
+        assert_eq!(fts_query("a AND b").unwrap(), r#""a" "AND" "b"*"#);
+        assert_eq!(fts_query("NEAR(a b)").unwrap(), r#""NEAR(a" "b)"*"#);
+        assert_eq!(fts_query("*").unwrap(), r#""*"*"#);
+        assert!(fts_query("").is_none());
+        assert!(fts_query(" \t\n").is_none());
+    }
+
+    #[test]
+    fn search_syntax_in_input_is_matched_as_text() {
+        let mut harness = Harness::new();
+        let (alice, alice_receiver) = harness.user("alice@example.com");
+        let chat_id = harness.new_chat(&alice, &alice_receiver);
+        let message = NetworkMessage::NewMessage(
+            alice.clone(),
+            "user".to_string(),
+            chat_id,
+            r#"she said "AND" NEAR the * end"#.to_string(),
+            uuid::Uuid::new_v4().to_string(),
+            false,
+            None,
+        );
+        harness.request(&alice_receiver, message);
+
+        let inputs = [r#"said "AND"#, "AND", "NEAR(the", "the OR", "*", "e\"n\"d"];
+        for input in inputs {
+            let query = fts_query(input).unwrap();
+            let sql = "select count(*) from MessagesSearch where MessagesSearch match ?";
+            let mut statement = harness.db.connection.prepare(sql).unwrap();
+            statement.bind((1, query.as_str())).unwrap();
+            assert!(statement.next().is_ok(), "{input} is not a valid query");
+        }
+        for input in [r#"said "AND"#, "AND", "NEAR(the", "she"] {
+            let message = NetworkMessage::Search(alice.clone(), input.to_string(), 10);
+            match harness.request(&alice_receiver, message) {
+                DatabaseMessage::SearchResults(results) => assert_eq!(results.len(), 1, "{input}"),
+                response => panic!("unexpected {response:?}"),
+            }
+        }
+        let message = NetworkMessage::Search(alice.clone(), "   ".to_string(), 10);
+        match harness.request(&alice_receiver, message) {
+            DatabaseMessage::SearchResults(results) => assert!(results.is_empty()),
+            response => panic!("unexpected {response:?}"),
+        }
+    }
+
+    #[test]
+    fn messages_of_other_users_are_denied() {
+        let mut harness = Harness::new();
+        let (alice, alice_receiver) = harness.user("alice@example.com");
+        let (bob, bob_receiver) = harness.user("bob@example.com");
+        let (_, message_id) = harness.first_message(&alice, &alice_receiver);
+
+        let message = NetworkMessage::GetMessage(bob.clone(), message_id.clone());
+        let response = harness.request(&bob_receiver, message);
+        assert!(matches!(response, DatabaseMessage::Err));
+
+        let message = NetworkMessage::GetMessage(alice.clone(), message_id);
+        let response = harness.request(&alice_receiver, message);
+        assert!(matches!(response, DatabaseMessage::Message(_)));
+    }
+
+    #[test]
+    fn audio_of_other_users_is_denied() {
+        let mut harness = Harness::new();
+        let (alice, alice_receiver) = harness.user("alice@example.com");
+        let (bob, bob_receiver) = harness.user("bob@example.com");
+        let (_, message_id) = harness.first_message(&alice, &alice_receiver);
+
+        // Recording audio for someone else's message is ignored.
+        let message = NetworkMessage::RecordAudioPath(
+            bob.clone(),
+            message_id.clone(),
+            "static/bob".to_string(),
+        );
+        harness.network.send(message).unwrap();
+        let message = NetworkMessage::GetAudioPath(alice.clone(), message_id.clone());
+        let response = harness.request(&alice_receiver, message);
+        assert!(matches!(response, DatabaseMessage::Err));
+
+        let message = NetworkMessage::RecordAudioPath(
+            alice.clone(),
+            message_id.clone(),
+            "static/alice".to_string(),
+        );
+        harness.network.send(message).unwrap();
+        let message = NetworkMessage::GetAudioPath(bob.clone(), message_id.clone());
+        let response = harness.request(&bob_receiver, message);
+        assert!(matches!(response, DatabaseMessage::Err));
+
+        let message = NetworkMessage::GetAudioPath(alice.clone(), message_id);
+        match harness.request(&alice_receiver, message) {
+            DatabaseMessage::AudioPath(path) => assert_eq!(path, "static/alice"),
+            response => panic!("unexpected {response:?}"),
+        }
+    }
+
+    #[test]
+    fn chats_of_other_users_are_denied() {
+        let mut harness = Harness::new();
+        let (alice, alice_receiver) = harness.user("alice@example.com");
+        let (bob, bob_receiver) = harness.user("bob@example.com");
+        let (chat_id, _) = harness.first_message(&alice, &alice_receiver);
+
+        let requests = [
+            NetworkMessage::ChatRequest(bob.clone(), chat_id.clone()),
+            NetworkMessage::GetMessagePage(
+                bob.clone(),
+                chat_id.clone(),
+                PageRequest {
+                    cursor: None,
+                    direction: Direction::Before,
+                    limit: 50,
+                },
+            ),
+            NetworkMessage::GetSummary(bob.clone(), chat_id.clone()),
+            NetworkMessage::GetChatSettings(bob.clone(), chat_id.clone()),
+            NetworkMessage::Regenerate(bob.clone(), chat_id.clone()),
+            NetworkMessage::RenameChat(
+                bob.clone(),
+                ChatTitle {
+                    chat_id: chat_id.clone(),
+                    title: "mine".to_string(),
+                },
+                false,
+            ),
+            NetworkMessage::DeleteChat(bob.clone(), chat_id.clone()),
+        ];
+        for request in requests {
+            let response = harness.request(&bob_receiver, request);
+            assert!(matches!(response, DatabaseMessage::Err));
+        }
+        let response = harness.new_message(&bob, &bob_receiver, &chat_id);
+        assert!(matches!(response, DatabaseMessage::Err));
+
+        match harness.request(&bob_receiver, NetworkMessage::GetChats(bob.clone())) {
+            DatabaseMessage::Chats(chats) => assert!(chats.is_empty()),
+            response => panic!("unexpected {response:?}"),
+        }
+        let message = NetworkMessage::ChatRequest(alice.clone(), chat_id);
+        match harness.request(&alice_receiver, message) {
+            DatabaseMessage::Messages(_, messages) => assert_eq!(messages.len(), 1),
+            response => panic!("unexpected {response:?}"),
+        }
+    }
+
+    #[test]
+    fn unauthenticated_connections_are_denied() {
+        let mut harness = Harness::new();
+        let (alice, alice_receiver) = harness.user("alice@example.com");
+        let (stranger, receiver) = harness.connect();
+        let (chat_id, message_id) = harness.first_message(&alice, &alice_receiver);
+
+        let requests = [
+            NetworkMessage::GetChats(stranger.clone()),
+            NetworkMessage::ChatRequest(stranger.clone(), chat_id),
+            NetworkMessage::GetMessage(stranger.clone(), message_id.clone()),
+            NetworkMessage::GetAudioPath(stranger.clone(), message_id),
+        ];
+        for request in requests {
+            let response = harness.request(&receiver, request);
+            assert!(matches!(response, DatabaseMessage::Err));
+        }
+    }
+}
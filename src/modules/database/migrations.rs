@@ -0,0 +1,218 @@
+use sqlite::Connection;
+
+// Every entry is applied once, in order, and its position (starting at 1) is
+// the schema version it leaves the database at. Never edit a shipped entry,
+// append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: the original schema. Uses `if not exists` so databases created before
+    // `schema_version` existed are adopted as-is.
+    "
+    create table if not exists Users (
+        email text primary key,
+        password text not null
+    );
+    create table if not exists UserInfo (
+        email text primary key,
+        name text not null
+    );
+    create table if not exists Tokens (
+        token text primary key,
+        email text not null,
+        expire integer not null
+    );
+    create table if not exists Chats (
+        email text not null,
+        chat_id text primary key
+    );
+    create table if not exists Messages (
+        email text not null,
+        chat_id text not null,
+        sender text not null,
+        content text not null,
+        datetime integer not null,
+        id text primary key
+    );
+    create table if not exists AudioPaths (
+        id text primary key,
+        path text not null
+    );
+    create index if not exists messages_chat on Messages (email, chat_id, datetime);
+    create index if not exists tokens_email on Tokens (email);
+    ",
+    // 2: answers cut short by a cancelled generation.
+    "alter table Messages add column truncated integer not null default 0;",
+    // 3: rolling summaries of the history that no longer fits the context window.
+    "
+    create table if not exists Summaries (
+        email text not null,
+        chat_id text primary key,
+        content text not null,
+        until_id text not null
+    );
+    ",
+    // 4: per-chat system prompts and the per-user persona library.
+    "
+    alter table Chats add column system_prompt text;
+    alter table Chats add column persona text;
+    create table if not exists Personas (
+        email text not null,
+        name text not null,
+        prompt text not null,
+        primary key (email, name)
+    );
+    ",
+    // 5: per-chat model and sampling settings, and the model behind each answer.
+    "
+    alter table Chats add column model text;
+    alter table Chats add column temperature real;
+    alter table Chats add column top_p real;
+    alter table Chats add column max_tokens integer;
+    alter table Chats add column stop text;
+    alter table Messages add column model text;
+    ",
+    // 6: chat titles and activity times for the chat list.
+    "
+    alter table Chats add column title text;
+    alter table Chats add column created_at integer not null default 0;
+    alter table Chats add column updated_at integer not null default 0;
+    update Chats set
+        created_at = coalesce(
+            (select min(datetime) from Messages where Messages.chat_id = Chats.chat_id),
+            cast(strftime('%s', 'now') as integer)
+        ),
+        updated_at = coalesce(
+            (select max(datetime) from Messages where Messages.chat_id = Chats.chat_id),
+            cast(strftime('%s', 'now') as integer)
+        );
+    create index if not exists chats_activity on Chats (email, updated_at);
+    ",
+    // 7: full-text search over message contents, kept in sync by triggers.
+    "
+    create virtual table if not exists MessagesSearch using fts5(
+        content,
+        email unindexed,
+        chat_id unindexed,
+        id unindexed,
+        datetime unindexed,
+        content = 'Messages',
+        content_rowid = 'rowid'
+    );
+    create trigger if not exists messages_search_insert after insert on Messages begin
+        insert into MessagesSearch (rowid, content, email, chat_id, id, datetime)
+        values (new.rowid, new.content, new.email, new.chat_id, new.id, new.datetime);
+    end;
+    create trigger if not exists messages_search_delete after delete on Messages begin
+        insert into MessagesSearch (MessagesSearch, rowid, content, email, chat_id, id, datetime)
+        values ('delete', old.rowid, old.content, old.email, old.chat_id, old.id, old.datetime);
+    end;
+    create trigger if not exists messages_search_update after update of content on Messages begin
+        insert into MessagesSearch (MessagesSearch, rowid, content, email, chat_id, id, datetime)
+        values ('delete', old.rowid, old.content, old.email, old.chat_id, old.id, old.datetime);
+        insert into MessagesSearch (rowid, content, email, chat_id, id, datetime)
+        values (new.rowid, new.content, new.email, new.chat_id, new.id, new.datetime);
+    end;
+    insert into MessagesSearch (MessagesSearch) values ('rebuild');
+    ",
+    // 8: messages form a tree through their parent, and every chat points at
+    // the last message of its active branch. Existing chats become one branch.
+    "
+    alter table Messages add column parent_id text;
+    alter table Chats add column head_id text;
+    update Messages set parent_id = (
+        select previous.id from Messages previous
+        where previous.email = Messages.email and previous.chat_id = Messages.chat_id
+            and (previous.datetime < Messages.datetime
+                or (previous.datetime = Messages.datetime and previous.rowid < Messages.rowid))
+        order by previous.datetime desc, previous.rowid desc
+        limit 1
+    );
+    update Chats set head_id = (
+        select id from Messages
+        where Messages.email = Chats.email and Messages.chat_id = Chats.chat_id
+        order by datetime desc, rowid desc
+        limit 1
+    );
+    create index if not exists messages_parent on Messages (parent_id);
+    ",
+    // 9: one session per signed in device instead of one token per user.
+    // Old tokens were issued for a day, which dates them well enough.
+    "
+    create table Sessions (
+        id text primary key,
+        email text not null,
+        token text not null unique,
+        device text,
+        created_at integer not null,
+        last_seen integer not null,
+        expire integer not null
+    );
+    insert into Sessions (id, email, token, created_at, last_seen, expire)
+    select lower(hex(randomblob(16))), email, token, expire - 86400, expire - 86400, expire
+    from Tokens;
+    drop table Tokens;
+    create index if not exists sessions_email on Sessions (email);
+    ",
+    // 10: rotating refresh tokens. `Sessions.expire` now bounds the access
+    // token and `refresh_expire` the session itself. Used refresh tokens are
+    // kept until their session ends so that reusing one can be detected.
+    "
+    alter table Sessions add column refresh_expire integer not null default 0;
+    update Sessions set refresh_expire = expire;
+    create table RefreshTokens (
+        token text primary key,
+        session_id text not null,
+        used integer not null default 0
+    );
+    create index if not exists refresh_tokens_session on RefreshTokens (session_id);
+    ",
+    // 11: personal API keys for the OpenAI compatible endpoint, stored hashed.
+    "
+    create table ApiKeys (
+        id text primary key,
+        email text not null,
+        key_hash text not null unique,
+        created_at integer not null
+    );
+    create index if not exists api_keys_email on ApiKeys (email);
+    ",
+    // 12: named API keys with scopes, usage and expiry. Keys issued so far
+    // were only good for completions.
+    "
+    alter table ApiKeys add column name text not null default '';
+    alter table ApiKeys add column scopes text not null default '[\"completions\"]';
+    alter table ApiKeys add column last_used integer;
+    alter table ApiKeys add column expires_at integer;
+    ",
+];
+
+pub fn migrate(connection: &Connection) -> sqlite::Result<()> {
+    connection.execute("create table if not exists schema_version (version integer not null)")?;
+    let current = schema_version(connection)?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current) {
+        let version = index + 1;
+        connection.execute("begin")?;
+        let result = connection.execute(migration).and_then(|_| {
+            connection.execute(format!(
+                "delete from schema_version; insert into schema_version values ({version})"
+            ))
+        });
+        match result {
+            Ok(_) => connection.execute("commit")?,
+            Err(error) => {
+                let _ = connection.execute("rollback");
+                return Err(error);
+            }
+        }
+        println!("database migrated to version {version}");
+    }
+    Ok(())
+}
+
+fn schema_version(connection: &Connection) -> sqlite::Result<usize> {
+    let query = "select version from schema_version";
+    let mut statement = connection.prepare(query)?;
+    match statement.iter().next() {
+        Some(row) => Ok(row?.read::<i64, _>("version") as usize),
+        None => Ok(0),
+    }
+}
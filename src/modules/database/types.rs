@@ -1,11 +1,20 @@
 use crate::modules::web_client::types::*;
+use std::sync::mpsc::Receiver;
+
+// What a connection hears from the database: the replies to its own requests
+// in order, and apart from them the changes other devices of the user make.
+pub struct Channels {
+    pub replies: Receiver<DatabaseMessage>,
+    pub pushes: Receiver<DatabaseMessage>,
+}
 
 #[derive(Debug)]
 pub enum DatabaseMessage {
-    Chats(Vec<String>),
+    Chats(Vec<ChatEntry>),
     Messages(String, Vec<WebMessage>),
+    MessagePage(MessagePage),
     Email(String),
-    Token(String),
+    Token(Credentials),
     Timestamp(u64),
     Message(Message),
     AudioPath(String),
@@ -13,20 +22,76 @@ pub enum DatabaseMessage {
     Deleted(String),
     NewChat(String),
     WebMessage(WebMessage),
+    Summary(Summary),
+    SystemPrompt(String),
+    ChatPrompt(ChatPrompt),
+    Personas(Vec<Persona>),
+    ChatSettings(ChatSettings),
+    ChatTitle(ChatTitle),
+    SearchResults(Vec<SearchResult>),
+    // The last user message, its answers and the head before the rewind.
+    Rewound(WebMessage, Vec<String>, Option<String>),
+    PasswordHash(String),
+    TokenExpired,
+    // The session of the connection was revoked and it has to close.
+    Revoked,
+    Sessions(Vec<Session>),
+    ApiKeyCreated(NewApiKey),
+    ApiKeys(Vec<ApiKey>),
+    // The connection authenticated with an API key that lacks the scope for
+    // the request.
+    MissingScope,
     Ok,
     Err,
 }
 
+// The first field is the id of the connection the request comes from. Apart
+// from the login flow, requests act as the user that connection authenticated
+// as, see `Authenticate`, and only reach chats and messages of that user.
 pub enum NetworkMessage {
-    ChatRequest(String, String, String),
-    LoginRequest(String, String, String),
-    TokenValidation(String, String),
-    NewChat(String, String),
-    NewMessage(String, String, String, String, String, String),
-    GetChats(String, String),
-    DeleteChat(String, String, String),
-    RegisterUser(String, String, String, String),
+    ChatRequest(String, String),
+    GetMessagePage(String, String, PageRequest),
+    // Sent once the password matched the hash from `GetPasswordHash`, with a
+    // replacement hash when the stored one is outdated.
+    LoginRequest(String, String, Option<String>, Option<String>),
+    GetPasswordHash(String, String),
+    Refresh(String, String),
+    // Accepts access tokens and API keys alike.
+    Authenticate(String, String),
+    // Like `Authenticate`, for connections that make one request at a time and
+    // so must not get the pushes meant for the other devices of the user.
+    Authorize(String, String),
+    NewChat(String),
+    NewMessage(String, String, String, String, String, bool, Option<String>),
+    GetChats(String),
+    DeleteChat(String, String),
+    RegisterUser(String, String, String, String, Option<String>),
     GetMessage(String, String),
     GetAudioPath(String, String),
-    RecordAudioPath(String, String),
+    RecordAudioPath(String, String, String),
+    GetSummary(String, String),
+    SaveSummary(String, String, Summary),
+    GetSystemPrompt(String, String),
+    SetSystemPrompt(String, ChatPrompt),
+    GetPersonas(String),
+    SavePersona(String, Persona),
+    DeletePersona(String, String),
+    GetChatSettings(String, String),
+    SetChatSettings(String, ChatSettings),
+    RenameChat(String, ChatTitle, bool),
+    Search(String, String, u64),
+    EditMessage(String, String, String, String, String),
+    SwitchBranch(String, String, String),
+    Regenerate(String, String),
+    GetSessions(String),
+    RevokeSession(String, String),
+    LogoutAll(String),
+    CreateApiKey(String, CreateApiKey),
+    GetApiKeys(String),
+    RevokeApiKey(String, String),
+    // Answered with `Ok` when the connection may use `Scope`, on the chat if
+    // one is given.
+    CheckScope(String, Scope, Option<String>),
+    // The connection is gone and its id may be forgotten.
+    Disconnect(String),
 }
@@ -3,10 +3,15 @@ use std::collections::HashMap;
 #[derive(Debug)]
 pub struct Env {
     api_key: String,
+    provider: String,
+    provider_url: Option<String>,
     text_model: String,
+    allowed_models: Vec<String>,
     voice_model: String,
+    speech_api_key: String,
     pub context_size: u64,
     pub answer_max: u64,
+    tokenizer_vocab: Option<String>,
     google_api_key: String,
     voice: String,
     project_id: String,
@@ -16,12 +21,36 @@ impl Env {
     pub fn new() -> Self {
         dotenvy::dotenv_override().unwrap();
         let vars: HashMap<String, String> = dotenvy::vars().collect::<HashMap<String, String>>();
+        let provider = vars
+            .get("PROVIDER")
+            .map(|x| x.to_lowercase())
+            .unwrap_or("fireworks".into());
+        // Transcriptions always go to Groq, so the chat key is only reused
+        // when it is a Groq key.
+        let speech_api_key = match vars.get("SPEECH_API_KEY") {
+            Some(key) => key.clone(),
+            None if provider == "groq" => vars.get("API_KEY").cloned().unwrap_or_default(),
+            None => String::new(),
+        };
         Self {
-            api_key: vars.get("API_KEY").unwrap().into(),
+            api_key: vars.get("API_KEY").cloned().unwrap_or_default(),
+            provider,
+            provider_url: vars.get("PROVIDER_URL").cloned(),
             text_model: vars.get("TEXT_MODEL").unwrap().into(),
+            allowed_models: vars
+                .get("ALLOWED_MODELS")
+                .map(|x| {
+                    x.split(',')
+                        .map(|x| x.trim().to_string())
+                        .filter(|x| !x.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
             voice_model: vars.get("VOICE_MODEL").unwrap().into(),
+            speech_api_key,
             context_size: vars.get("CONTEXT_SIZE").unwrap().parse::<u64>().unwrap(),
             answer_max: vars.get("ANSWER_MAX").unwrap().parse::<u64>().unwrap(),
+            tokenizer_vocab: vars.get("TOKENIZER_VOCAB").cloned(),
             google_api_key: vars.get("GOOGLE_API_KEY").unwrap().into(),
             voice: vars.get("VOICE").unwrap().into(),
             project_id: vars.get("PROJECT_ID").unwrap().into(),
@@ -32,6 +61,17 @@ impl Env {
         self.text_model.clone()
     }
 
+    /// Models chats may select, always including the default `TEXT_MODEL`.
+    pub fn allowed_models(&self) -> Vec<String> {
+        let mut models = vec![self.text_model.clone()];
+        for model in &self.allowed_models {
+            if !models.contains(model) {
+                models.push(model.clone());
+            }
+        }
+        models
+    }
+
     pub fn voice_model(&self) -> String {
         self.voice_model.clone()
     }
@@ -40,6 +80,22 @@ impl Env {
         self.api_key.clone()
     }
 
+    pub fn speech_api_key(&self) -> String {
+        self.speech_api_key.clone()
+    }
+
+    pub fn provider(&self) -> String {
+        self.provider.clone()
+    }
+
+    pub fn provider_url(&self) -> Option<String> {
+        self.provider_url.clone()
+    }
+
+    pub fn tokenizer_vocab(&self) -> Option<String> {
+        self.tokenizer_vocab.clone()
+    }
+
     pub fn voice(&self) -> String {
         self.voice.clone()
     }
@@ -0,0 +1,109 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2, Params,
+};
+use sha2::Digest;
+
+pub enum Verification {
+    Valid,
+    /// The password matches, but the stored hash should be replaced with a
+    /// fresh one from `hash`.
+    Outdated,
+    Invalid,
+}
+
+/// Argon2id with the crate's default cost and a random salt, encoded as a
+/// PHC string that carries both.
+pub fn hash(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Unable to hash password")
+        .to_string()
+}
+
+/// Checks `password` against a stored hash. Besides PHC strings this accepts
+/// the unsalted hex SHA-256 hashes written before Argon2id was used.
+pub fn verify(password: &str, stored: &str) -> Verification {
+    let Ok(parsed) = PasswordHash::new(stored) else {
+        let mut sha = sha2::Sha256::new();
+        sha.update(password);
+        return if hex::encode(sha.finalize()) == stored {
+            Verification::Outdated
+        } else {
+            Verification::Invalid
+        };
+    };
+    if Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_err()
+    {
+        return Verification::Invalid;
+    }
+    let current = Params::default();
+    match Params::try_from(&parsed) {
+        Ok(params)
+            if parsed.algorithm == argon2::Algorithm::Argon2id.ident()
+                && params.m_cost() == current.m_cost()
+                && params.t_cost() == current.t_cost()
+                && params.p_cost() == current.p_cost() =>
+        {
+            Verification::Valid
+        }
+        _ => Verification::Outdated,
+    }
+}
+
+// A hash of `pw123456` with the default cost, which unknown emails are
+// checked against. Hashing it on demand would make the first one stand out.
+const DUMMY: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$3ClfPwxicF5jmtXN4QYOVg$w4KLn+0/cKeYW6IMHTZtR8bcuN+uaQMxT4IpAZwm+Iw";
+
+/// Takes as long as `verify` with a wrong password, for logins with an email
+/// nobody registered. Answering those faster would tell which emails are.
+pub fn verify_unknown(password: &str) {
+    verify(password, DUMMY);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::{Algorithm, Version};
+
+    // Hex SHA-256 of `hunter2`, as stored before Argon2id.
+    const LEGACY: &str = "f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7";
+
+    #[test]
+    fn verifies_its_own_hashes() {
+        let stored = hash("hunter2");
+        assert!(stored.starts_with("$argon2id$"));
+        assert!(matches!(verify("hunter2", &stored), Verification::Valid));
+        assert_ne!(hash("hunter2"), stored);
+    }
+
+    #[test]
+    fn rejects_wrong_passwords() {
+        let stored = hash("hunter2");
+        assert!(matches!(verify("hunter3", &stored), Verification::Invalid));
+        assert!(matches!(verify("", &stored), Verification::Invalid));
+        assert!(matches!(verify("hunter3", LEGACY), Verification::Invalid));
+    }
+
+    #[test]
+    fn unknown_emails_are_checked_at_the_current_cost() {
+        assert!(matches!(verify("pw123456", DUMMY), Verification::Valid));
+    }
+
+    #[test]
+    fn reports_legacy_and_weaker_hashes_as_outdated() {
+        assert!(matches!(verify("hunter2", LEGACY), Verification::Outdated));
+
+        let params = Params::new(Params::MIN_M_COST, 1, 1, None).unwrap();
+        let salt = SaltString::generate(&mut OsRng);
+        let weak = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        assert!(matches!(verify("hunter2", &weak), Verification::Outdated));
+    }
+}
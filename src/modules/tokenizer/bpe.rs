@@ -0,0 +1,157 @@
+use base64::Engine;
+use std::collections::HashMap;
+
+/// Byte-level BPE over a tiktoken style vocabulary: one `<base64 bytes> <rank>`
+/// pair per line, lower ranks merging first.
+pub struct Bpe {
+    ranks: HashMap<Vec<u8>, u32>,
+}
+
+impl Bpe {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path).map_err(|x| x.to_string())?;
+        let mut ranks = HashMap::new();
+        for line in data.lines() {
+            let Some((token, rank)) = line.split_once(' ') else {
+                continue;
+            };
+            let token = base64::engine::general_purpose::STANDARD
+                .decode(token)
+                .map_err(|x| x.to_string())?;
+            let rank = rank.trim().parse::<u32>().map_err(|x| x.to_string())?;
+            ranks.insert(token, rank);
+        }
+        if ranks.is_empty() {
+            return Err("empty vocabulary".into());
+        }
+        Ok(Self { ranks })
+    }
+
+    pub fn encode_len(&self, text: &str) -> usize {
+        pieces(text)
+            .into_iter()
+            .map(|piece| self.piece_len(piece.as_bytes()))
+            .sum()
+    }
+
+    fn piece_len(&self, piece: &[u8]) -> usize {
+        if self.ranks.contains_key(piece) {
+            return 1;
+        }
+        // Boundaries between the current parts, merged pairwise by lowest rank.
+        let mut bounds = (0..=piece.len()).collect::<Vec<usize>>();
+        loop {
+            let mut best: Option<(u32, usize)> = None;
+            for i in 0..bounds.len().saturating_sub(2) {
+                let pair = &piece[bounds[i]..bounds[i + 2]];
+                if let Some(&rank) = self.ranks.get(pair) {
+                    if best.is_none_or(|(x, _)| rank < x) {
+                        best = Some((rank, i));
+                    }
+                }
+            }
+            match best {
+                Some((_, i)) => {
+                    bounds.remove(i + 1);
+                }
+                None => break,
+            }
+        }
+        bounds.len() - 1
+    }
+}
+
+#[derive(PartialEq)]
+enum Class {
+    Letter,
+    Digit,
+    Space,
+    Other,
+}
+
+fn class(c: char) -> Class {
+    if c.is_alphabetic() {
+        Class::Letter
+    } else if c.is_numeric() {
+        Class::Digit
+    } else if c.is_whitespace() {
+        Class::Space
+    } else {
+        Class::Other
+    }
+}
+
+// Approximates the tiktoken pre-tokenizer: words keep one leading space,
+// digits are grouped by three and runs of whitespace or symbols stay together.
+fn pieces(text: &str) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut run: Option<Class> = None;
+    let mut len = 0;
+    for (index, c) in text.char_indices() {
+        let kind = class(c);
+        match run {
+            Some(Class::Space) if kind != Class::Space && text[..index].ends_with(' ') => {
+                let space = index - 1;
+                if space > start {
+                    pieces.push(&text[start..space]);
+                    start = space;
+                }
+                len = 0;
+            }
+            Some(ref previous) if *previous != kind || (kind == Class::Digit && len == 3) => {
+                pieces.push(&text[start..index]);
+                start = index;
+                len = 0;
+            }
+            _ => {}
+        }
+        run = Some(kind);
+        len += 1;
+    }
+    if start < text.len() {
+        pieces.push(&text[start..]);
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vocab(ranks: &[(&str, u32)]) -> Bpe {
+        let ranks = ranks
+            .iter()
+            .map(|(token, rank)| (token.as_bytes().to_vec(), *rank))
+            .collect();
+        Bpe { ranks }
+    }
+
+    #[test]
+    fn splits_words_digits_spaces_and_symbols() {
+        assert_eq!(pieces("Hello world"), vec!["Hello", " world"]);
+        assert_eq!(pieces("a  b"), vec!["a", " ", " b"]);
+        assert_eq!(pieces("x 12345"), vec!["x", " 123", "45"]);
+        assert_eq!(pieces("hi!?"), vec!["hi", "!?"]);
+        assert!(pieces("").is_empty());
+    }
+
+    #[test]
+    fn merges_the_lowest_rank_first() {
+        // `ab` first still lets `cd` merge, `bc` first leaves no known pair.
+        let ab_first = vocab(&[("ab", 0), ("bc", 1), ("cd", 2)]);
+        assert_eq!(ab_first.encode_len("abcd"), 2);
+        let bc_first = vocab(&[("bc", 0), ("ab", 1), ("cd", 2)]);
+        assert_eq!(bc_first.encode_len("abcd"), 3);
+        let whole = vocab(&[("ab", 0), ("cd", 1), ("abcd", 2)]);
+        assert_eq!(whole.encode_len("abcd"), 1);
+    }
+
+    #[test]
+    fn counts_unknown_bytes_one_each() {
+        let bpe = vocab(&[("ab", 0), (" ab", 1)]);
+        assert_eq!(bpe.encode_len("ab ab"), 2);
+        assert_eq!(bpe.encode_len("abz"), 2);
+        assert_eq!(bpe.encode_len("é"), 2);
+    }
+}
@@ -0,0 +1,94 @@
+use crate::modules::env::env::Env;
+use crate::modules::tokenizer::bpe::Bpe;
+use crate::modules::web_client::types::Message;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+// Tokens chat templates spend around every message for the role and separators.
+const MESSAGE_OVERHEAD: u64 = 4;
+
+pub trait Tokenizer: Send + Sync {
+    fn count(&self, text: &str) -> u64;
+
+    fn count_message(&self, message: &Message) -> u64 {
+        let role = message.role.as_deref().map_or(0, |x| self.count(x));
+        let content = message.content.as_deref().map_or(0, |x| self.count(x));
+        role + content + MESSAGE_OVERHEAD
+    }
+}
+
+/// Roughly four characters per token, which holds well enough for English
+/// and Portuguese text when no vocabulary is configured.
+pub struct Heuristic;
+
+impl Tokenizer for Heuristic {
+    fn count(&self, text: &str) -> u64 {
+        (text.chars().count() as u64).div_ceil(4)
+    }
+}
+
+impl Tokenizer for Bpe {
+    fn count(&self, text: &str) -> u64 {
+        self.encode_len(text) as u64
+    }
+}
+
+/// Returns the BPE tokenizer for `TOKENIZER_VOCAB` when it is set and loads,
+/// and the heuristic otherwise. Vocabularies are parsed once per process.
+pub fn from_env(env: &Env) -> Arc<dyn Tokenizer> {
+    static LOADED: OnceLock<Mutex<HashMap<String, Arc<Bpe>>>> = OnceLock::new();
+    let Some(path) = env.tokenizer_vocab() else {
+        return Arc::new(Heuristic);
+    };
+    let mut loaded = LOADED
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap();
+    if let Some(bpe) = loaded.get(&path) {
+        return bpe.clone();
+    }
+    match Bpe::load(&path) {
+        Ok(bpe) => {
+            let bpe = Arc::new(bpe);
+            loaded.insert(path, bpe.clone());
+            bpe
+        }
+        Err(error) => {
+            println!("unable to load tokenizer vocabulary {path}: {error}");
+            Arc::new(Heuristic)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heuristic_counts_four_characters_per_token() {
+        assert_eq!(Heuristic.count(""), 0);
+        assert_eq!(Heuristic.count("abcd"), 1);
+        assert_eq!(Heuristic.count("abcde"), 2);
+        assert_eq!(Heuristic.count("ãããã"), 1);
+    }
+
+    #[test]
+    fn counts_messages_with_their_overhead() {
+        let path = std::env::temp_dir().join(format!("vocab-{}", uuid::Uuid::new_v4()));
+        // `user`, `hi` and ` there`.
+        std::fs::write(&path, "dXNlcg== 0\naGk= 1\nIHRoZXJl 2\n").unwrap();
+        let bpe = Bpe::load(path.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let message = Message::new("user", "hi there");
+        assert_eq!(bpe.count_message(&message), 3 + MESSAGE_OVERHEAD);
+        assert_eq!(Heuristic.count_message(&message), 1 + 2 + MESSAGE_OVERHEAD);
+        let empty = Message {
+            content: None,
+            role: None,
+        };
+        assert_eq!(bpe.count_message(&empty), MESSAGE_OVERHEAD);
+    }
+}
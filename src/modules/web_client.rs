@@ -1,5 +1,9 @@
 pub mod client;
 pub mod google_types;
 pub mod http;
+pub mod ollama_types;
+pub mod protocol;
+pub mod provider;
+pub mod rest;
 pub mod server;
 pub mod types;
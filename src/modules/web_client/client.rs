@@ -1,14 +1,30 @@
 use crate::modules::env::env::Env;
-use crate::modules::web_client::{google_types::*, types::*};
+use crate::modules::tokenizer::tokenizer::{self, Tokenizer};
+use crate::modules::web_client::{
+    google_types::*,
+    provider::{self, ChatProvider, Completion},
+    types::*,
+};
 use reqwest::blocking::Client;
 use serde_json::json;
+use std::sync::Arc;
+
+const TITLE_PROMPT: &str = "Write a short title, at most six words, for the conversation below. Use the language of the conversation. Answer with the title only, without quotes.";
+const TITLE_MAX_CHARS: usize = 100;
+const SUMMARY_PROMPT: &str = "You keep a running summary of a conversation between a user and an assistant. Update the current summary with the new messages, keeping names, facts, decisions and open questions. Answer with the updated summary only.";
 
 pub struct WebClient {
     env: Env,
     pub chat_id: String,
+    pub system_prompt: Option<String>,
+    pub settings: Option<ChatSettings>,
     context: Messages,
+    summary: Option<Summary>,
+    // Set when `summary` changed and still has to be persisted.
+    summary_changed: bool,
     client: Client,
-    text_completion_uri: String,
+    provider: Box<dyn ChatProvider>,
+    tokenizer: Arc<dyn Tokenizer>,
     speech_to_text_uri: String,
     text_to_speech_uri: String,
 }
@@ -17,58 +33,242 @@ impl WebClient {
     pub fn new() -> Self {
         let env = Env::new();
         let client = Client::new();
+        let provider = provider::from_env(&env);
+        let tokenizer = tokenizer::from_env(&env);
         Self {
             client,
             env,
+            provider,
+            tokenizer,
             chat_id: String::new(),
+            system_prompt: None,
+            settings: None,
             context: Messages::new(Vec::new()),
-            text_completion_uri: "https://api.fireworks.ai/inference/v1/chat/completions".into(),
+            summary: None,
+            summary_changed: false,
             speech_to_text_uri: "https://api.groq.com/openai/v1/audio/transcriptions".into(),
             text_to_speech_uri: "https://texttospeech.googleapis.com/v1/text:synthesize".into(),
         }
     }
 
-    pub fn load_context(&mut self, context: Messages) {
+    pub fn load_context(&mut self, context: Messages, summary: Option<Summary>) {
         self.context = context;
+        // A summary written on another branch does not describe this one.
+        self.summary = summary.filter(|x| self.context.messages.iter().any(|m| m.id == x.until));
+        self.summary_changed = false;
         self.context.max_tokens = self.env.context_size;
         self.context.answer_tokens = self.env.answer_max;
     }
 
-    pub fn new_message(&mut self, message: WebMessage) -> Option<Message> {
+    /// The summary produced by the last `new_message`, if it has not been
+    /// handed out yet.
+    pub fn take_summary(&mut self) -> Option<Summary> {
+        if !self.summary_changed {
+            return None;
+        }
+        self.summary_changed = false;
+        self.summary.clone()
+    }
+
+    pub fn new_message(
+        &mut self,
+        message: WebMessage,
+        answer_id: &str,
+        mut on_delta: impl FnMut(&str) -> bool,
+    ) -> Option<Completion> {
         self.env = Env::new();
-        let content_len = message.message.content.as_ref().unwrap().len();
-        self.context.push(message);
-        let request = GroqTextRequest::new(
-            self.context.get_window(content_len as u64),
-            self.env.text_model(),
-        );
-        if let Ok(response) = self
-            .client
-            .post(&self.text_completion_uri)
-            .header("Content-Type", "application/json")
-            .header("Authorization", &format!("Bearer {}", self.env.api_key()))
-            .json(&request)
-            .send()
-        {
-            if response.status().is_success() {
-                if let Ok(mut response) = response.json::<ApiResponse>() {
-                    let message = response.choices.pop().unwrap().message;
-                    let timestamp = response.created;
-                    let id = uuid::Uuid::new_v4().to_string();
-                    self.context
-                        .push(WebMessage::new(message.clone(), timestamp as u64, id));
-                    Some(message)
-                } else {
-                    println!("IS NOT APIRESPONSE");
-                    None
-                }
-            } else {
-                println!("ERROR:");
-                return None;
+        self.provider = provider::from_env(&self.env);
+        self.tokenizer = tokenizer::from_env(&self.env);
+        let (model, sampling) = self.model_settings();
+        self.context.max_tokens = self.env.context_size;
+        self.context.answer_tokens = sampling.max_tokens.unwrap_or(self.env.answer_max);
+        // A context reloaded from the database already holds the new message.
+        if self.context.messages.last().map(|x| &x.id) != Some(&message.id) {
+            self.context.push(message);
+        }
+        let reserved = self
+            .system_message()
+            .iter()
+            .chain(self.summary_message().iter())
+            .map(|x| self.tokenizer.count_message(x))
+            .sum();
+        let window = self.context.get_window(self.tokenizer.as_ref(), reserved);
+        self.summarize(self.context.messages.len() - window.len());
+        let mut messages = Vec::new();
+        messages.extend(self.system_message());
+        messages.extend(self.summary_message());
+        messages.extend(window.into_iter().map(|x| x.message));
+        let mut completion =
+            self.provider
+                .stream(&self.client, &model, &sampling, messages, &mut on_delta)?;
+        // Streamed answers carry no usage from the provider, so count it here.
+        if completion.usage.total_tokens == 0 {
+            let prompt_tokens = self.context.used_tokens();
+            let completion_tokens = self.tokenizer.count_message(&completion.message);
+            completion.usage = Usage {
+                prompt_tokens: prompt_tokens as i32,
+                completion_tokens: completion_tokens as i32,
+                total_tokens: (prompt_tokens + completion_tokens) as i32,
+            };
+        }
+        self.context.push(WebMessage::new(
+            completion.message.clone(),
+            completion.created,
+            answer_id.to_string(),
+        ));
+        Some(completion)
+    }
+
+    /// Sends `messages` as they are, without the context, prompt or summary of
+    /// a chat, for callers that keep the history themselves. Streams when
+    /// `on_delta` is given.
+    pub fn complete(
+        &mut self,
+        model: &str,
+        sampling: &Sampling,
+        messages: Vec<Message>,
+        on_delta: Option<&mut dyn FnMut(&str) -> bool>,
+    ) -> Option<Completion> {
+        self.env = Env::new();
+        self.provider = provider::from_env(&self.env);
+        self.tokenizer = tokenizer::from_env(&self.env);
+        let prompt_tokens: u64 = messages
+            .iter()
+            .map(|x| self.tokenizer.count_message(x))
+            .sum();
+        let mut completion = match on_delta {
+            Some(on_delta) => {
+                self.provider
+                    .stream(&self.client, model, sampling, messages, on_delta)?
             }
-        } else {
+            None => self
+                .provider
+                .complete(&self.client, model, sampling, messages)?,
+        };
+        if completion.usage.total_tokens == 0 {
+            let completion_tokens = self.tokenizer.count_message(&completion.message);
+            completion.usage = Usage {
+                prompt_tokens: prompt_tokens as i32,
+                completion_tokens: completion_tokens as i32,
+                total_tokens: (prompt_tokens + completion_tokens) as i32,
+            };
+        }
+        Some(completion)
+    }
+
+    /// Whether the context holds nothing but the first question and its answer.
+    pub fn is_first_exchange(&self) -> bool {
+        self.summary.is_none() && self.context.messages.len() == 2
+    }
+
+    pub fn generate_title(&mut self) -> Option<String> {
+        let transcript = self
+            .context
+            .messages
+            .iter()
+            .map(|x| {
+                format!(
+                    "{}: {}",
+                    x.message.role.as_deref().unwrap_or_default(),
+                    x.message.content.as_deref().unwrap_or_default()
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        let request = vec![
+            Message::new("system".to_string(), TITLE_PROMPT.to_string()),
+            Message::new("user".to_string(), transcript),
+        ];
+        let completion = self.provider.complete(
+            &self.client,
+            &self.env.text_model(),
+            &Sampling::default(),
+            request,
+        )?;
+        let title = completion.message.content?;
+        let title = title.trim().trim_matches('"').trim();
+        if title.is_empty() {
             return None;
         }
+        Some(title.chars().take(TITLE_MAX_CHARS).collect())
+    }
+
+    // The chat's model when the allowlist still permits it, and its sampling.
+    fn model_settings(&self) -> (String, Sampling) {
+        let Some(ref settings) = self.settings else {
+            return (self.env.text_model(), Sampling::default());
+        };
+        let model = settings
+            .model
+            .clone()
+            .filter(|x| self.env.allowed_models().contains(x))
+            .unwrap_or(self.env.text_model());
+        (model, settings.sampling.clone())
+    }
+
+    fn system_message(&self) -> Option<Message> {
+        let prompt = self.system_prompt.as_ref()?;
+        Some(Message::new("system", prompt.as_str()))
+    }
+
+    fn summary_message(&self) -> Option<Message> {
+        let summary = self.summary.as_ref()?;
+        Some(Message::new(
+            "system".to_string(),
+            format!("Summary of the earlier conversation:\n{}", summary.content),
+        ))
+    }
+
+    // Folds the first `dropped` messages of the context, the ones that no
+    // longer fit the window, into the running summary of the chat.
+    fn summarize(&mut self, dropped: usize) {
+        let prefix = &self.context.messages[..dropped];
+        let start = match self.summary {
+            Some(ref summary) => match prefix.iter().position(|x| x.id == summary.until) {
+                Some(index) => index + 1,
+                // Already covers more than what was dropped.
+                None if self.context.messages.iter().any(|x| x.id == summary.until) => dropped,
+                None => 0,
+            },
+            None => 0,
+        };
+        if start >= dropped {
+            return;
+        }
+        let transcript = prefix[start..]
+            .iter()
+            .map(|x| {
+                format!(
+                    "{}: {}",
+                    x.message.role.as_deref().unwrap_or_default(),
+                    x.message.content.as_deref().unwrap_or_default()
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        let previous = match (start, &self.summary) {
+            (0, _) | (_, None) => "(none)".to_string(),
+            (_, Some(summary)) => summary.content.clone(),
+        };
+        let request = vec![
+            Message::new("system", SUMMARY_PROMPT),
+            Message::new(
+                "user".to_string(),
+                format!("Current summary:\n{previous}\n\nNew messages:\n{transcript}"),
+            ),
+        ];
+        let until = prefix[dropped - 1].id.clone();
+        if let Some(completion) = self.provider.complete(
+            &self.client,
+            &self.env.text_model(),
+            &Sampling::default(),
+            request,
+        ) {
+            if let Some(content) = completion.message.content {
+                self.summary = Some(Summary::new(content.trim().to_string(), until));
+                self.summary_changed = true;
+            }
+        }
     }
 
     pub fn new_audio(&mut self, message: String) -> Option<String> {
@@ -96,4 +296,61 @@ impl WebClient {
             return None;
         }
     }
+
+    pub fn transcribe(&mut self, audio: &[u8], format: &str) -> Option<String> {
+        // The format ends up in a multipart header.
+        if !AUDIO_FORMATS.contains(&format) {
+            return None;
+        }
+        self.env = Env::new();
+        if self.env.speech_api_key().is_empty() {
+            println!("SPEECH_API_KEY is not set, transcription is disabled");
+            return None;
+        }
+        let boundary = format!("----{}", uuid::Uuid::new_v4().simple());
+        let body = multipart_body(
+            &boundary,
+            &[("model", &self.env.voice_model())],
+            ("file", audio, &format!("voice.{format}")),
+        );
+        let response = self
+            .client
+            .post(&self.speech_to_text_uri)
+            .header(
+                "Content-Type",
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .bearer_auth(self.env.speech_api_key())
+            .body(body)
+            .send()
+            .ok()?;
+        if !response.status().is_success() {
+            println!("transcription returned {}", response.status());
+            return None;
+        }
+        let transcription = response.json::<TranscriptionResponse>().ok()?;
+        Some(transcription.text.trim().to_string())
+    }
+}
+
+fn multipart_body(boundary: &str, fields: &[(&str, &str)], file: (&str, &[u8], &str)) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+            )
+            .as_bytes(),
+        );
+    }
+    let (name, content, filename) = file;
+    body.extend_from_slice(
+        format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"; filename=\"{filename}\"\r\nContent-Type: application/octet-stream\r\n\r\n"
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(content);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    body
 }
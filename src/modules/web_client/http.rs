@@ -1,6 +1,6 @@
 pub fn get_header(headers: &[httparse::Header], target: &str) -> Option<String> {
     for header in headers.iter() {
-        if header.name == target {
+        if header.name.eq_ignore_ascii_case(target) {
             return Some(String::from_utf8_lossy(header.value).to_string());
         }
     }
@@ -17,16 +17,16 @@ pub fn new_headers<'a>(headers: &'a Vec<(&'a str, &'a str)>) -> Vec<httparse::He
         .collect::<Vec<httparse::Header>>()
 }
 
-pub fn response_to_string<T: AsRef<[u8]>>(response: httparse::Response, body: Option<T>) -> String {
+pub fn response_to_bytes<T: AsRef<[u8]>>(response: httparse::Response, body: Option<T>) -> Vec<u8> {
     let code = format!(
-        "HTTP/1.1 {} {}\n",
+        "HTTP/1.1 {} {}\r\n",
         response.code.unwrap(),
         response.reason.unwrap()
     );
     let mut headers = Vec::new();
     for header in response.headers.iter() {
         let header = format!(
-            "{}: {}\n",
+            "{}: {}\r\n",
             header.name,
             String::from_utf8_lossy(header.value).to_string()
         );
@@ -38,10 +38,9 @@ pub fn response_to_string<T: AsRef<[u8]>>(response: httparse::Response, body: Op
         .map(|x| x.as_bytes().to_vec())
         .collect::<Vec<Vec<u8>>>();
     let headers = headers.concat();
-    let mut response = vec![code, headers];
+    let mut response = vec![code, headers, "\r\n".as_bytes().to_vec()];
     if let Some(body) = body {
-        response.push("\n".as_bytes().to_vec());
         response.push(body.as_ref().to_vec());
     }
-    String::from_utf8_lossy(&response.concat()).to_string()
+    response.concat()
 }
@@ -0,0 +1,53 @@
+use crate::modules::web_client::types::{Message, Sampling};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OllamaChatRequest {
+    model: String,
+    messages: Vec<Message>,
+    stream: bool,
+    options: OllamaOptions,
+}
+
+impl OllamaChatRequest {
+    pub fn new(messages: Vec<Message>, model: String, sampling: &Sampling) -> Self {
+        Self {
+            model,
+            messages,
+            stream: false,
+            options: OllamaOptions {
+                temperature: sampling.temperature,
+                top_p: sampling.top_p,
+                num_predict: sampling.max_tokens,
+                stop: sampling.stop.clone(),
+            },
+        }
+    }
+
+    pub fn streaming(mut self) -> Self {
+        self.stream = true;
+        self
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OllamaChatResponse {
+    pub model: String,
+    pub message: Message,
+    pub done: bool,
+    // Set on the last chunk, `stop` or `length` like the OpenAI field.
+    #[serde(default)]
+    pub done_reason: Option<String>,
+}
@@ -0,0 +1,128 @@
+use crate::modules::web_client::types::{ClientMessage, ServerFrame, ServerResponse};
+use schemars::schema_for;
+use serde_json::{json, Map, Value};
+
+// Every frame is a `{type, payload}` envelope, plus the `request_id` the
+// client chose for the request and the server echoes on its answers.
+pub const CURRENT_VERSION: u32 = 2;
+// Version 1 is the externally tagged `{kind, body: {<type>: <payload>}}`
+// layout from before versioning, kept for clients that ask for no version.
+// It is converted at the edge, so nothing else has to know about it.
+pub const OLDEST_VERSION: u32 = 1;
+
+pub fn subprotocol(version: u32) -> String {
+    format!("assistant.v{version}")
+}
+
+// Picks the version from the WebSocket subprotocols the client offers, the
+// newest one both sides speak. `None` when it offers only unknown ones.
+pub fn negotiate(offered: &[String]) -> Option<u32> {
+    if offered.is_empty() {
+        return Some(OLDEST_VERSION);
+    }
+    (OLDEST_VERSION..=CURRENT_VERSION)
+        .rev()
+        .find(|version| offered.contains(&subprotocol(*version)))
+}
+
+pub fn parse(version: u32, data: &str) -> serde_json::Result<ClientMessage> {
+    let value = serde_json::from_str::<Value>(data)?;
+    if version == 1 {
+        return serde_json::from_value(upgrade(value));
+    }
+    serde_json::from_value(value)
+}
+
+pub fn encode(version: u32, response: ServerResponse, request_id: Option<&str>) -> String {
+    let frame = ServerFrame {
+        request_id: request_id.map(|x| x.to_string()),
+        response,
+    };
+    let value = json!(frame);
+    if version == 1 {
+        return downgrade(value).to_string();
+    }
+    value.to_string()
+}
+
+// The JSON Schema of every frame in the current version, for both directions.
+pub fn schema() -> Value {
+    json!({
+        "version": CURRENT_VERSION,
+        "subprotocol": subprotocol(CURRENT_VERSION),
+        "client": schema_for!(ClientMessage),
+        "server": schema_for!(ServerFrame),
+    })
+}
+
+// `{kind, request_id, body: {<type>: <payload>}}` to `{type, payload, request_id}`.
+fn upgrade(value: Value) -> Value {
+    let Value::Object(mut object) = value else {
+        return value;
+    };
+    object.remove("kind");
+    if let Some(Value::Object(body)) = object.remove("body") {
+        if let Some((kind, payload)) = body.into_iter().next() {
+            object.insert("type".to_string(), Value::String(kind));
+            object.insert("payload".to_string(), payload);
+        }
+    }
+    Value::Object(object)
+}
+
+// `{type, payload, request_id}` to `{<type>: <payload>, request_id}`.
+fn downgrade(value: Value) -> Value {
+    let Value::Object(mut object) = value else {
+        return value;
+    };
+    let mut frame = Map::new();
+    if let (Some(Value::String(kind)), Some(payload)) =
+        (object.remove("type"), object.remove("payload"))
+    {
+        frame.insert(kind, payload);
+    }
+    frame.extend(object);
+    Value::Object(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::web_client::types::ClientMessageKind;
+
+    #[test]
+    fn negotiates_the_newest_offered_version() {
+        assert_eq!(negotiate(&[]), Some(1));
+        let offered = vec![subprotocol(1), subprotocol(2)];
+        assert_eq!(negotiate(&offered), Some(2));
+        assert_eq!(negotiate(&[subprotocol(1)]), Some(1));
+        assert_eq!(negotiate(&["assistant.v99".to_string()]), None);
+    }
+
+    #[test]
+    fn parses_both_layouts() {
+        let current = r#"{"type": "delete_chat", "payload": {"chat_id": "a"}, "request_id": "1"}"#;
+        let legacy = r#"{"kind": "", "body": {"delete_chat": {"token": "t", "chat_id": "a"}}, "request_id": "1"}"#;
+        for message in [parse(2, current), parse(1, legacy)] {
+            let message = message.unwrap();
+            assert_eq!(message.request_id.as_deref(), Some("1"));
+            let ClientMessageKind::DeleteChat(delete) = message.body else {
+                panic!("unexpected {:?}", message.body);
+            };
+            assert_eq!(delete.chat_id, "a");
+        }
+        assert!(parse(2, legacy).is_err());
+    }
+
+    #[test]
+    fn encodes_both_layouts() {
+        let response = || ServerResponse::Deleted("a".to_string());
+        let current: Value = serde_json::from_str(&encode(2, response(), Some("1"))).unwrap();
+        assert_eq!(
+            current,
+            json!({"type": "deleted", "payload": "a", "request_id": "1"})
+        );
+        let legacy: Value = serde_json::from_str(&encode(1, response(), None)).unwrap();
+        assert_eq!(legacy, json!({"deleted": "a"}));
+    }
+}
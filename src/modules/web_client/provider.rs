@@ -0,0 +1,374 @@
+use crate::modules::env::env::Env;
+use crate::modules::web_client::{ollama_types::*, types::*};
+use reqwest::blocking::Client;
+use std::io::{BufRead, BufReader};
+use std::time::Duration;
+
+const STREAM_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub struct Completion {
+    pub message: Message,
+    pub model: String,
+    pub created: u64,
+    pub truncated: bool,
+    // Why the provider stopped, in its own words, e.g. `stop` or `length`.
+    pub finish_reason: Option<String>,
+    pub usage: Usage,
+}
+
+pub trait ChatProvider: Send {
+    fn name(&self) -> &'static str;
+    fn complete(
+        &self,
+        client: &Client,
+        model: &str,
+        sampling: &Sampling,
+        messages: Vec<Message>,
+    ) -> Option<Completion>;
+
+    /// Like `complete`, but calls `on_delta` with every piece of content as the
+    /// provider produces it. Returning `false` from `on_delta` drops the upstream
+    /// request and yields what was generated so far, marked as truncated.
+    fn stream(
+        &self,
+        client: &Client,
+        model: &str,
+        sampling: &Sampling,
+        messages: Vec<Message>,
+        on_delta: &mut dyn FnMut(&str) -> bool,
+    ) -> Option<Completion> {
+        let mut completion = self.complete(client, model, sampling, messages)?;
+        if let Some(ref content) = completion.message.content {
+            completion.truncated = !on_delta(content);
+        }
+        Some(completion)
+    }
+}
+
+pub fn from_env(env: &Env) -> Box<dyn ChatProvider> {
+    match env.provider().as_str() {
+        "groq" => Box::new(Groq::new(env.api_key())),
+        "openai" => Box::new(OpenAiCompatible::new(
+            env.provider_url()
+                .unwrap_or("https://api.openai.com/v1".into()),
+            env.api_key(),
+        )),
+        "ollama" => Box::new(Ollama::new(
+            env.provider_url()
+                .unwrap_or("http://localhost:11434".into()),
+        )),
+        _ => Box::new(Fireworks::new(env.api_key())),
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+pub struct OpenAiCompatible {
+    base_url: String,
+    api_key: String,
+}
+
+impl OpenAiCompatible {
+    pub fn new(base_url: String, api_key: String) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+        }
+    }
+
+    fn completions_uri(&self) -> String {
+        format!("{}/chat/completions", self.base_url)
+    }
+}
+
+impl ChatProvider for OpenAiCompatible {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn complete(
+        &self,
+        client: &Client,
+        model: &str,
+        sampling: &Sampling,
+        messages: Vec<Message>,
+    ) -> Option<Completion> {
+        let request = ChatCompletionRequest::new(messages, model.to_string(), sampling);
+        let mut builder = client
+            .post(self.completions_uri())
+            .header("Content-Type", "application/json")
+            .json(&request);
+        if !self.api_key.is_empty() {
+            builder = builder.bearer_auth(&self.api_key);
+        }
+        let response = builder.send().ok()?;
+        if !response.status().is_success() {
+            println!("{} returned {}", self.name(), response.status());
+            return None;
+        }
+        let mut response = response.json::<ApiResponse>().ok()?;
+        let choice = response.choices.pop()?;
+        Some(Completion {
+            model: model.to_string(),
+            message: choice.message,
+            created: response.created as u64,
+            truncated: false,
+            finish_reason: Some(choice.finish_reason),
+            usage: response.usage,
+        })
+    }
+
+    fn stream(
+        &self,
+        client: &Client,
+        model: &str,
+        sampling: &Sampling,
+        messages: Vec<Message>,
+        on_delta: &mut dyn FnMut(&str) -> bool,
+    ) -> Option<Completion> {
+        let request = ChatCompletionRequest::new(messages, model.to_string(), sampling).streaming();
+        let mut builder = client
+            .post(self.completions_uri())
+            .header("Content-Type", "application/json")
+            .header("Accept", "text/event-stream")
+            .timeout(STREAM_TIMEOUT)
+            .json(&request);
+        if !self.api_key.is_empty() {
+            builder = builder.bearer_auth(&self.api_key);
+        }
+        let response = builder.send().ok()?;
+        if !response.status().is_success() {
+            println!("{} returned {}", self.name(), response.status());
+            return None;
+        }
+        let mut content = String::new();
+        let mut created = now();
+        let mut truncated = false;
+        let mut finish_reason = None;
+        for line in BufReader::new(response).lines() {
+            // Keep what already reached the client if the connection drops.
+            let Ok(line) = line else {
+                truncated = true;
+                break;
+            };
+            let Some(data) = line.strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                break;
+            }
+            let Ok(chunk) = serde_json::from_str::<ChatCompletionChunk>(data) else {
+                continue;
+            };
+            created = chunk.created as u64;
+            for choice in chunk.choices {
+                if choice.finish_reason.is_some() {
+                    finish_reason = choice.finish_reason;
+                }
+                if let Some(delta) = choice.delta.content {
+                    if !delta.is_empty() {
+                        content.push_str(&delta);
+                        if !on_delta(&delta) {
+                            truncated = true;
+                        }
+                    }
+                }
+            }
+            if truncated {
+                break;
+            }
+        }
+        Some(Completion {
+            model: model.to_string(),
+            message: Message::new("assistant", content.as_str()),
+            created,
+            truncated,
+            finish_reason,
+            usage: Usage::default(),
+        })
+    }
+}
+
+pub struct Fireworks(OpenAiCompatible);
+
+impl Fireworks {
+    pub fn new(api_key: String) -> Self {
+        Self(OpenAiCompatible::new(
+            "https://api.fireworks.ai/inference/v1".into(),
+            api_key,
+        ))
+    }
+}
+
+impl ChatProvider for Fireworks {
+    fn name(&self) -> &'static str {
+        "fireworks"
+    }
+
+    fn complete(
+        &self,
+        client: &Client,
+        model: &str,
+        sampling: &Sampling,
+        messages: Vec<Message>,
+    ) -> Option<Completion> {
+        self.0.complete(client, model, sampling, messages)
+    }
+
+    fn stream(
+        &self,
+        client: &Client,
+        model: &str,
+        sampling: &Sampling,
+        messages: Vec<Message>,
+        on_delta: &mut dyn FnMut(&str) -> bool,
+    ) -> Option<Completion> {
+        self.0.stream(client, model, sampling, messages, on_delta)
+    }
+}
+
+pub struct Groq(OpenAiCompatible);
+
+impl Groq {
+    pub fn new(api_key: String) -> Self {
+        Self(OpenAiCompatible::new(
+            "https://api.groq.com/openai/v1".into(),
+            api_key,
+        ))
+    }
+}
+
+impl ChatProvider for Groq {
+    fn name(&self) -> &'static str {
+        "groq"
+    }
+
+    fn complete(
+        &self,
+        client: &Client,
+        model: &str,
+        sampling: &Sampling,
+        messages: Vec<Message>,
+    ) -> Option<Completion> {
+        self.0.complete(client, model, sampling, messages)
+    }
+
+    fn stream(
+        &self,
+        client: &Client,
+        model: &str,
+        sampling: &Sampling,
+        messages: Vec<Message>,
+        on_delta: &mut dyn FnMut(&str) -> bool,
+    ) -> Option<Completion> {
+        self.0.stream(client, model, sampling, messages, on_delta)
+    }
+}
+
+pub struct Ollama {
+    base_url: String,
+}
+
+impl Ollama {
+    pub fn new(base_url: String) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn chat_uri(&self) -> String {
+        format!("{}/api/chat", self.base_url)
+    }
+}
+
+impl ChatProvider for Ollama {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn complete(
+        &self,
+        client: &Client,
+        model: &str,
+        sampling: &Sampling,
+        messages: Vec<Message>,
+    ) -> Option<Completion> {
+        let request = OllamaChatRequest::new(messages, model.to_string(), sampling);
+        let response = client.post(self.chat_uri()).json(&request).send().ok()?;
+        if !response.status().is_success() {
+            println!("{} returned {}", self.name(), response.status());
+            return None;
+        }
+        let response = response.json::<OllamaChatResponse>().ok()?;
+        Some(Completion {
+            model: model.to_string(),
+            message: response.message,
+            created: now(),
+            truncated: false,
+            finish_reason: response.done_reason,
+            usage: Usage::default(),
+        })
+    }
+
+    fn stream(
+        &self,
+        client: &Client,
+        model: &str,
+        sampling: &Sampling,
+        messages: Vec<Message>,
+        on_delta: &mut dyn FnMut(&str) -> bool,
+    ) -> Option<Completion> {
+        let request = OllamaChatRequest::new(messages, model.to_string(), sampling).streaming();
+        let response = client
+            .post(self.chat_uri())
+            .timeout(STREAM_TIMEOUT)
+            .json(&request)
+            .send()
+            .ok()?;
+        if !response.status().is_success() {
+            println!("{} returned {}", self.name(), response.status());
+            return None;
+        }
+        let mut content = String::new();
+        let mut truncated = false;
+        let mut finish_reason = None;
+        for line in BufReader::new(response).lines() {
+            // Keep what already reached the client if the connection drops.
+            let Ok(line) = line else {
+                truncated = true;
+                break;
+            };
+            let Ok(chunk) = serde_json::from_str::<OllamaChatResponse>(&line) else {
+                continue;
+            };
+            if let Some(delta) = chunk.message.content {
+                if !delta.is_empty() {
+                    content.push_str(&delta);
+                    if !on_delta(&delta) {
+                        truncated = true;
+                    }
+                }
+            }
+            if chunk.done_reason.is_some() {
+                finish_reason = chunk.done_reason;
+            }
+            if chunk.done || truncated {
+                break;
+            }
+        }
+        Some(Completion {
+            model: model.to_string(),
+            message: Message::new("assistant", content.as_str()),
+            created: now(),
+            truncated,
+            finish_reason,
+            usage: Usage::default(),
+        })
+    }
+}
@@ -0,0 +1,911 @@
+use crate::modules::database::types::*;
+use crate::modules::env::env::Env;
+use crate::modules::password::password::{self, Verification};
+use crate::modules::web_client::{
+    client::WebClient,
+    http::*,
+    provider::Completion,
+    server::{audio_file, load_chat, reply, Registrar, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE},
+    types::*,
+};
+use base64::Engine;
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{Receiver, Sender},
+    time::Duration,
+};
+
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+// Each connection holds a thread, which a client that stops sending would
+// keep forever.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Plain HTTP endpoints next to the WebSocket server, for scripts and tools
+// that make one request at a time:
+//
+//   POST   /login                      Login             -> UserInfo
+//   POST   /register                   Register          -> UserInfo
+//   POST   /refresh                    Refresh           -> Credentials
+//   GET    /chats                                        -> [ChatEntry]
+//   POST   /chats                                        -> {chat_id}
+//   DELETE /chats/{chat_id}
+//   GET    /chats/{chat_id}/messages   ?cursor&direction&limit -> MessagePage
+//   POST   /chats/{chat_id}/messages   PostMessage       -> WebMessage
+//   GET    /messages/{message_id}/audio                  -> audio/mpeg
+//   GET    /keys                                         -> [ApiKey]
+//   POST   /keys                       CreateApiKey      -> NewApiKey
+//   DELETE /keys/{key_id}
+//
+// All but the first three take `Authorization: Bearer <token>`, with an
+// access token or an API key that has the scopes for the request.
+// Errors carry the same `code`, `message` and `retryable` as error frames.
+//
+// Under `/v1` the assistant itself is served the way OpenAI's API serves
+// models, so existing tools can point at it with a `completions` key.
+// Access tokens are refused there:
+//
+//   GET    /v1/models
+//   POST   /v1/chat/completions        CompletionRequest
+pub struct RestServer {
+    registrar: Registrar,
+    network_sender: Sender<NetworkMessage>,
+}
+
+impl RestServer {
+    pub fn new(registrar: Registrar, network_sender: Sender<NetworkMessage>) -> Self {
+        Self {
+            registrar,
+            network_sender,
+        }
+    }
+
+    pub fn update(&mut self) {
+        let listener = TcpListener::bind("0.0.0.0:8081").expect("Unable to start HTTP server");
+        for stream in listener.incoming().flatten() {
+            let addr = uuid::Uuid::new_v4().to_string();
+            // Connections authorize without pushes, so only replies arrive.
+            let channels = self.registrar.register(&addr);
+            let mut connection =
+                RestConnection::new(stream, addr, self.network_sender.clone(), channels.replies);
+            std::thread::spawn(move || {
+                connection.update();
+            });
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+// Serves a single request. Every request gets its own database connection,
+// which is bound to the session of its access token and dropped afterwards.
+pub struct RestConnection {
+    stream: TcpStream,
+    addr: String,
+    sender: Sender<NetworkMessage>,
+    receiver: Receiver<DatabaseMessage>,
+    // Set once a streamed response started, which leaves nothing to write.
+    sent: bool,
+}
+
+impl RestConnection {
+    pub fn new(
+        stream: TcpStream,
+        addr: String,
+        sender: Sender<NetworkMessage>,
+        receiver: Receiver<DatabaseMessage>,
+    ) -> Self {
+        Self {
+            stream,
+            addr,
+            sender,
+            receiver,
+            sent: false,
+        }
+    }
+
+    pub fn update(&mut self) {
+        let reply = match read_request(&mut self.stream) {
+            Some(request) => self.route(&request),
+            None => error(ErrorCode::BadRequest, "Malformed request"),
+        };
+        self.write(reply);
+        let _ = self
+            .sender
+            .send(NetworkMessage::Disconnect(self.addr.clone()));
+    }
+
+    fn route(&mut self, request: &Request) -> Reply {
+        let path = request.path.trim_matches('/');
+        let segments: Vec<&str> = path.split('/').collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("POST", ["login"]) => self.login(&request.body),
+            ("POST", ["register"]) => self.register(&request.body),
+            ("POST", ["refresh"]) => self.refresh(&request.body),
+            ("GET", ["chats"]) => self.authorized(request, |this| this.get_chats()),
+            ("POST", ["chats"]) => self.authorized(request, |this| this.new_chat()),
+            ("DELETE", ["chats", chat_id]) => {
+                self.authorized(request, |this| this.delete_chat(chat_id))
+            }
+            ("GET", ["chats", chat_id, "messages"]) => {
+                self.authorized(request, |this| this.get_messages(chat_id, &request.query))
+            }
+            ("POST", ["chats", chat_id, "messages"]) => {
+                self.authorized(request, |this| this.post_message(chat_id, &request.body))
+            }
+            ("GET", ["messages", message_id, "audio"]) => {
+                self.authorized(request, |this| this.get_audio(message_id))
+            }
+            ("GET", ["keys"]) => self.authorized(request, |this| this.get_api_keys()),
+            ("POST", ["keys"]) => {
+                self.authorized(request, |this| this.create_api_key(&request.body))
+            }
+            ("DELETE", ["keys", key_id]) => {
+                self.authorized(request, |this| this.revoke_api_key(key_id))
+            }
+            ("GET", ["v1", "models"]) => self.with_api_key(request, |_| models()),
+            ("POST", ["v1", "chat", "completions"]) => {
+                self.with_api_key(request, |this| this.chat_completions(&request.body))
+            }
+            (_, ["v1", ..]) => openai_error(ErrorCode::NotFound, "No such endpoint"),
+            _ => error(ErrorCode::NotFound, "No such endpoint"),
+        }
+    }
+
+    // Binds the connection to the session of the bearer token before handing
+    // the request to `handler`.
+    fn authorized(&mut self, request: &Request, handler: impl FnOnce(&mut Self) -> Reply) -> Reply {
+        let token = request
+            .authorization
+            .as_deref()
+            .and_then(|x| x.strip_prefix("Bearer "));
+        let Some(token) = token else {
+            return error(ErrorCode::Unauthorized, "Missing access token");
+        };
+        let _ = self.sender.send(NetworkMessage::Authorize(
+            self.addr.clone(),
+            token.trim().to_string(),
+        ));
+        match reply(&self.receiver) {
+            DatabaseMessage::Email(_) => handler(self),
+            response => deny(response, ErrorCode::Unauthorized, "Invalid access token"),
+        }
+    }
+
+    // Like `authorized`, for the `/v1` endpoints. Only API keys with the
+    // `completions` scope get through, access tokens do not.
+    fn with_api_key(
+        &mut self,
+        request: &Request,
+        handler: impl FnOnce(&mut Self) -> Reply,
+    ) -> Reply {
+        let key = request
+            .authorization
+            .as_deref()
+            .and_then(|x| x.strip_prefix("Bearer "));
+        let Some(key) = key else {
+            return openai_error(ErrorCode::Unauthorized, "Missing API key");
+        };
+        let _ = self.sender.send(NetworkMessage::Authorize(
+            self.addr.clone(),
+            key.trim().to_string(),
+        ));
+        let DatabaseMessage::Email(_) = reply(&self.receiver) else {
+            return openai_error(ErrorCode::Unauthorized, "Invalid API key");
+        };
+        let _ = self.sender.send(NetworkMessage::CheckScope(
+            self.addr.clone(),
+            Scope::Completions,
+            None,
+        ));
+        match reply(&self.receiver) {
+            DatabaseMessage::Ok => handler(self),
+            _ => openai_error(
+                ErrorCode::Forbidden,
+                "Completions take an API key with the completions scope",
+            ),
+        }
+    }
+
+    fn login(&mut self, body: &[u8]) -> Reply {
+        let Ok(login) = serde_json::from_slice::<Login>(body) else {
+            return error(ErrorCode::BadRequest, "Invalid login");
+        };
+        let _ = self.sender.send(NetworkMessage::GetPasswordHash(
+            self.addr.clone(),
+            login.email.clone(),
+        ));
+        let DatabaseMessage::PasswordHash(stored) = reply(&self.receiver) else {
+            password::verify_unknown(&login.password);
+            return error(ErrorCode::Unauthorized, "Wrong email or password");
+        };
+        let rehash = match password::verify(&login.password, &stored) {
+            Verification::Valid => None,
+            Verification::Outdated => Some(password::hash(&login.password)),
+            Verification::Invalid => {
+                return error(ErrorCode::Unauthorized, "Wrong email or password");
+            }
+        };
+        let _ = self.sender.send(NetworkMessage::LoginRequest(
+            self.addr.clone(),
+            login.email,
+            rehash,
+            login.device,
+        ));
+        match reply(&self.receiver) {
+            DatabaseMessage::UserInfo(info) => ok(200, &info),
+            response => deny(response, ErrorCode::Unauthorized, "Wrong email or password"),
+        }
+    }
+
+    fn register(&mut self, body: &[u8]) -> Reply {
+        let Ok(register) = serde_json::from_slice::<Register>(body) else {
+            return error(ErrorCode::BadRequest, "Invalid registration");
+        };
+        let _ = self.sender.send(NetworkMessage::RegisterUser(
+            self.addr.clone(),
+            register.name.to_string(),
+            register.email.to_string(),
+            password::hash(&register.password),
+            register.device.clone(),
+        ));
+        match reply(&self.receiver) {
+            DatabaseMessage::Token(credentials) => {
+                let info = UserInfo::new(register.email, register.name, credentials);
+                ok(201, &info)
+            }
+            response => deny(
+                response,
+                ErrorCode::Conflict,
+                "The email is already registered",
+            ),
+        }
+    }
+
+    fn refresh(&mut self, body: &[u8]) -> Reply {
+        let Ok(refresh) = serde_json::from_slice::<Refresh>(body) else {
+            return error(ErrorCode::BadRequest, "Invalid refresh");
+        };
+        let _ = self.sender.send(NetworkMessage::Refresh(
+            self.addr.clone(),
+            refresh.refresh_token,
+        ));
+        match reply(&self.receiver) {
+            DatabaseMessage::Token(credentials) => ok(200, &credentials),
+            _ => error(ErrorCode::Unauthorized, "Invalid refresh token"),
+        }
+    }
+
+    fn get_chats(&mut self) -> Reply {
+        let _ = self
+            .sender
+            .send(NetworkMessage::GetChats(self.addr.clone()));
+        match reply(&self.receiver) {
+            DatabaseMessage::Chats(chats) => ok(200, &chats),
+            response => deny(response, ErrorCode::Unauthorized, "Not authenticated"),
+        }
+    }
+
+    fn new_chat(&mut self) -> Reply {
+        let _ = self.sender.send(NetworkMessage::NewChat(self.addr.clone()));
+        match reply(&self.receiver) {
+            DatabaseMessage::NewChat(chat_id) => ok(201, &json!({ "chat_id": chat_id })),
+            response => deny(response, ErrorCode::Unauthorized, "Not authenticated"),
+        }
+    }
+
+    fn delete_chat(&mut self, chat_id: &str) -> Reply {
+        let _ = self.sender.send(NetworkMessage::DeleteChat(
+            self.addr.clone(),
+            chat_id.to_string(),
+        ));
+        match reply(&self.receiver) {
+            DatabaseMessage::Deleted(_) => Reply {
+                status: 204,
+                content_type: "application/json",
+                body: Vec::new(),
+            },
+            response => deny(response, ErrorCode::NotFound, "No such chat"),
+        }
+    }
+
+    fn get_messages(&mut self, chat_id: &str, query: &[(String, String)]) -> Reply {
+        let mut page = PageRequest {
+            cursor: None,
+            direction: Direction::default(),
+            limit: DEFAULT_PAGE_SIZE,
+        };
+        for (key, value) in query {
+            match key.as_str() {
+                "cursor" => page.cursor = Some(value.to_string()),
+                "direction" => match serde_json::from_value(json!(value)) {
+                    Ok(direction) => page.direction = direction,
+                    Err(_) => return error(ErrorCode::BadRequest, "Invalid direction"),
+                },
+                "limit" => match value.parse::<u64>() {
+                    Ok(limit) => page.limit = limit.clamp(1, MAX_PAGE_SIZE),
+                    Err(_) => return error(ErrorCode::BadRequest, "Invalid limit"),
+                },
+                _ => {}
+            }
+        }
+        let _ = self.sender.send(NetworkMessage::GetMessagePage(
+            self.addr.clone(),
+            chat_id.to_string(),
+            page,
+        ));
+        match reply(&self.receiver) {
+            DatabaseMessage::MessagePage(page) => ok(200, &page),
+            response => deny(response, ErrorCode::Forbidden, "No such chat"),
+        }
+    }
+
+    // Stores the question in the chat and answers with the answer once it
+    // is generated and stored as well, like a message sent over the socket.
+    fn post_message(&mut self, chat_id: &str, body: &[u8]) -> Reply {
+        let post = match serde_json::from_slice::<PostMessage>(body) {
+            Ok(post) => post,
+            Err(e) => return error(ErrorCode::BadRequest, &e.to_string()),
+        };
+        if post.content.trim().is_empty() {
+            return error(ErrorCode::BadRequest, "The message is empty");
+        }
+        let message_id = uuid::Uuid::new_v4().to_string();
+        let _ = self.sender.send(NetworkMessage::NewMessage(
+            self.addr.clone(),
+            "user".to_string(),
+            chat_id.to_string(),
+            post.content.clone(),
+            message_id.clone(),
+            false,
+            None,
+        ));
+        let timestamp = match reply(&self.receiver) {
+            DatabaseMessage::Timestamp(timestamp) => timestamp,
+            response => return deny(response, ErrorCode::Forbidden, "No such chat"),
+        };
+        let message = WebMessage::new(Message::new("user", &post.content), timestamp, message_id);
+        let mut web_client = WebClient::new();
+        load_chat(
+            &mut web_client,
+            &self.sender,
+            &self.receiver,
+            &self.addr,
+            chat_id,
+        );
+        let id = uuid::Uuid::new_v4().to_string();
+        let parent_id = message.id.clone();
+        let answer = web_client.new_message(message, &id, |_| true);
+        if let Some(summary) = web_client.take_summary() {
+            let _ = self.sender.send(NetworkMessage::SaveSummary(
+                self.addr.clone(),
+                chat_id.to_string(),
+                summary,
+            ));
+        }
+        let Some(answer) = answer else {
+            return error(ErrorCode::Upstream, "The model did not answer");
+        };
+        let _ = self.sender.send(NetworkMessage::NewMessage(
+            self.addr.clone(),
+            answer.message.role.clone().unwrap_or_default(),
+            chat_id.to_string(),
+            answer.message.content.clone().unwrap_or_default(),
+            id.clone(),
+            answer.truncated,
+            Some(answer.model.clone()),
+        ));
+        match reply(&self.receiver) {
+            DatabaseMessage::Timestamp(_) => {}
+            response => {
+                return deny(
+                    response,
+                    ErrorCode::Forbidden,
+                    "The answer could not be saved",
+                )
+            }
+        }
+        let mut done = WebMessage::new(answer.message, timestamp, id);
+        done.truncated = answer.truncated;
+        done.usage = Some(answer.usage);
+        done.model = Some(answer.model);
+        done.parent_id = Some(parent_id);
+        if web_client.is_first_exchange() {
+            if let Some(title) = web_client.generate_title() {
+                let title = ChatTitle {
+                    chat_id: chat_id.to_string(),
+                    title,
+                };
+                let _ =
+                    self.sender
+                        .send(NetworkMessage::RenameChat(self.addr.clone(), title, true));
+                reply(&self.receiver);
+            }
+        }
+        ok(201, &done)
+    }
+
+    // Audio is cached base64 encoded, as the TTS provider returns it, and is
+    // decoded here so the response is a playable file.
+    fn get_audio(&mut self, message_id: &str) -> Reply {
+        let mut web_client = WebClient::new();
+        let audio = match audio_file(
+            &mut web_client,
+            &self.sender,
+            &self.receiver,
+            &self.addr,
+            message_id,
+        ) {
+            Ok(audio) => audio,
+            Err(response) => return deny(*response, ErrorCode::Forbidden, "No such message"),
+        };
+        let audio = audio.and_then(|x| base64::engine::general_purpose::STANDARD.decode(x).ok());
+        match audio {
+            Some(audio) => Reply {
+                status: 200,
+                content_type: "audio/mpeg",
+                body: audio,
+            },
+            None => error(ErrorCode::Upstream, "Speech synthesis failed"),
+        }
+    }
+
+    fn get_api_keys(&mut self) -> Reply {
+        let _ = self
+            .sender
+            .send(NetworkMessage::GetApiKeys(self.addr.clone()));
+        match reply(&self.receiver) {
+            DatabaseMessage::ApiKeys(keys) => ok(200, &keys),
+            response => deny(response, ErrorCode::Unauthorized, "Not authenticated"),
+        }
+    }
+
+    fn create_api_key(&mut self, body: &[u8]) -> Reply {
+        let create = match serde_json::from_slice::<CreateApiKey>(body) {
+            Ok(create) => create,
+            Err(e) => return error(ErrorCode::BadRequest, &e.to_string()),
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if let Err(message) = create.validate(now) {
+            return error(ErrorCode::BadRequest, message);
+        }
+        let _ = self
+            .sender
+            .send(NetworkMessage::CreateApiKey(self.addr.clone(), create));
+        match reply(&self.receiver) {
+            DatabaseMessage::ApiKeyCreated(key) => ok(201, &key),
+            response => deny(response, ErrorCode::Unauthorized, "Not authenticated"),
+        }
+    }
+
+    fn revoke_api_key(&mut self, key_id: &str) -> Reply {
+        let _ = self.sender.send(NetworkMessage::RevokeApiKey(
+            self.addr.clone(),
+            key_id.to_string(),
+        ));
+        match reply(&self.receiver) {
+            DatabaseMessage::ApiKeys(_) => Reply {
+                status: 204,
+                content_type: "application/json",
+                body: Vec::new(),
+            },
+            response => deny(response, ErrorCode::Unauthorized, "Not authenticated"),
+        }
+    }
+
+    // Proxies the conversation to the configured provider. With a `chat_id`
+    // the last user message and the answer are stored in that chat as well.
+    fn chat_completions(&mut self, body: &[u8]) -> Reply {
+        let request = match serde_json::from_slice::<CompletionRequest>(body) {
+            Ok(request) => request,
+            Err(e) => return openai_error(ErrorCode::BadRequest, &e.to_string()),
+        };
+        if let Err(message) = request.sampling.validate() {
+            return openai_error(ErrorCode::BadRequest, message);
+        }
+        let messages = request.messages.into_iter().map(|x| x.flatten());
+        let messages = match messages.collect::<Result<Vec<Message>, _>>() {
+            Ok(messages) => messages,
+            Err(message) => return openai_error(ErrorCode::BadRequest, message),
+        };
+        if messages.is_empty() {
+            return openai_error(ErrorCode::BadRequest, "messages must not be empty");
+        }
+        let env = Env::new();
+        let model = match request.model {
+            Some(model) if env.allowed_models().contains(&model) => model,
+            Some(model) => {
+                let message = format!("The model `{model}` does not exist");
+                return openai_error(ErrorCode::NotFound, &message);
+            }
+            None => env.text_model(),
+        };
+        if let Some(ref chat_id) = request.chat_id {
+            let _ = self.sender.send(NetworkMessage::CheckScope(
+                self.addr.clone(),
+                Scope::Write,
+                Some(chat_id.clone()),
+            ));
+            match reply(&self.receiver) {
+                DatabaseMessage::Ok => {}
+                DatabaseMessage::MissingScope => {
+                    let message = "The API key does not allow writing to chats";
+                    return openai_error(ErrorCode::Forbidden, message);
+                }
+                _ => return openai_error(ErrorCode::Forbidden, "No such chat"),
+            }
+        }
+        // Only stored along with its answer, so failures leave the chat as is.
+        let question = messages
+            .iter()
+            .rev()
+            .find(|x| x.role.as_deref() == Some("user"))
+            .and_then(|x| x.content.clone());
+        let id = format!("chatcmpl-{}", uuid::Uuid::new_v4().simple());
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut web_client = WebClient::new();
+        let completion = if request.stream {
+            self.stream_completion(
+                &mut web_client,
+                &id,
+                created,
+                &model,
+                &request.sampling,
+                messages,
+            )
+        } else {
+            web_client.complete(&model, &request.sampling, messages, None)
+        };
+        let Some(completion) = completion else {
+            if self.sent {
+                let chunk = json!({ "error": { "message": "The model did not answer" } });
+                let _ = write!(self.stream, "data: {chunk}\n\ndata: [DONE]\n\n");
+            }
+            return openai_error(ErrorCode::Upstream, "The model did not answer");
+        };
+        if let (Some(ref chat_id), Some(question)) = (&request.chat_id, question) {
+            let message = Message::new("user".to_string(), question);
+            if let DatabaseMessage::Timestamp(_) = self.log_message(chat_id, message, None, false) {
+                self.log_message(
+                    chat_id,
+                    completion.message.clone(),
+                    Some(completion.model.clone()),
+                    completion.truncated,
+                );
+            }
+        }
+        let finish_reason = match completion.finish_reason.as_deref() {
+            Some("length") => "length",
+            Some("content_filter") => "content_filter",
+            _ => "stop",
+        };
+        if self.sent {
+            let chunk = json!({
+                "id": id,
+                "object": "chat.completion.chunk",
+                "created": created,
+                "model": completion.model,
+                "choices": [{ "index": 0, "delta": {}, "finish_reason": finish_reason }],
+                "usage": completion.usage,
+            });
+            let _ = write!(self.stream, "data: {chunk}\n\ndata: [DONE]\n\n");
+            return ok(200, &json!({}));
+        }
+        ok(
+            200,
+            &json!({
+                "id": id,
+                "object": "chat.completion",
+                "created": completion.created,
+                "model": completion.model,
+                "choices": [{
+                    "index": 0,
+                    "message": completion.message,
+                    "finish_reason": finish_reason,
+                }],
+                "usage": completion.usage,
+            }),
+        )
+    }
+
+    // Writes the answer as server-sent events while it is generated. The
+    // generation stops once the client went away.
+    fn stream_completion(
+        &mut self,
+        web_client: &mut WebClient,
+        id: &str,
+        created: u64,
+        model: &str,
+        sampling: &Sampling,
+        messages: Vec<Message>,
+    ) -> Option<Completion> {
+        let headers = vec![
+            ("Content-Type", "text/event-stream"),
+            ("Cache-Control", "no-cache"),
+            ("Connection", "close"),
+        ];
+        let mut headers = new_headers(&headers);
+        let response = httparse::Response {
+            version: Some(1),
+            code: Some(200),
+            reason: Some(reason(200)),
+            headers: &mut headers,
+        };
+        let _ = self
+            .stream
+            .write_all(&response_to_bytes(response, None::<&[u8]>));
+        self.sent = true;
+        let stream = &mut self.stream;
+        let mut send = |delta: serde_json::Value| {
+            let chunk = json!({
+                "id": id,
+                "object": "chat.completion.chunk",
+                "created": created,
+                "model": model,
+                "choices": [{ "index": 0, "delta": delta, "finish_reason": null }],
+            });
+            write!(stream, "data: {chunk}\n\n").is_ok()
+        };
+        send(json!({ "role": "assistant", "content": "" }));
+        let mut on_delta = |delta: &str| send(json!({ "content": delta }));
+        web_client.complete(model, sampling, messages, Some(&mut on_delta))
+    }
+
+    // Stores `message` in the chat `chat_id` as the newest message of its
+    // active branch, answering with the reply of the database.
+    fn log_message(
+        &mut self,
+        chat_id: &str,
+        message: Message,
+        model: Option<String>,
+        truncated: bool,
+    ) -> DatabaseMessage {
+        let _ = self.sender.send(NetworkMessage::NewMessage(
+            self.addr.clone(),
+            message.role.unwrap_or_default(),
+            chat_id.to_string(),
+            message.content.unwrap_or_default(),
+            uuid::Uuid::new_v4().to_string(),
+            truncated,
+            model,
+        ));
+        reply(&self.receiver)
+    }
+
+    fn write(&mut self, reply: Reply) {
+        if self.sent {
+            return;
+        }
+        let length = reply.body.len().to_string();
+        let headers = vec![
+            ("Content-Type", reply.content_type),
+            ("Content-Length", length.as_str()),
+            ("Connection", "close"),
+        ];
+        let mut headers = new_headers(&headers);
+        let response = httparse::Response {
+            version: Some(1),
+            code: Some(reply.status),
+            reason: Some(reason(reply.status)),
+            headers: &mut headers,
+        };
+        let _ = self
+            .stream
+            .write_all(&response_to_bytes(response, Some(&reply.body)));
+    }
+}
+
+fn models() -> Reply {
+    let models: Vec<serde_json::Value> = Env::new()
+        .allowed_models()
+        .into_iter()
+        .map(|x| json!({ "id": x, "object": "model", "created": 0, "owned_by": "system" }))
+        .collect();
+    ok(200, &json!({ "object": "list", "data": models }))
+}
+
+fn ok<T: Serialize>(status: u16, body: &T) -> Reply {
+    Reply {
+        status,
+        content_type: "application/json",
+        body: serde_json::to_vec(body).unwrap(),
+    }
+}
+
+fn error(code: ErrorCode, message: &str) -> Reply {
+    let body = json!({
+        "code": code,
+        "message": message,
+        "retryable": code.retryable(),
+    });
+    Reply {
+        status: status(code),
+        content_type: "application/json",
+        body: body.to_string().into_bytes(),
+    }
+}
+
+// An error in the shape OpenAI's API uses, which the `/v1` clients expect.
+fn openai_error(code: ErrorCode, message: &str) -> Reply {
+    let body = json!({
+        "error": {
+            "message": message,
+            "type": code,
+            "code": code,
+        },
+    });
+    Reply {
+        status: status(code),
+        content_type: "application/json",
+        body: body.to_string().into_bytes(),
+    }
+}
+
+// Like `error`, unless the request failed because the access token expired,
+// its session was revoked or its API key lacks the scope.
+fn deny(response: DatabaseMessage, code: ErrorCode, message: &str) -> Reply {
+    match response {
+        DatabaseMessage::TokenExpired => error(ErrorCode::TokenExpired, "The access token expired"),
+        DatabaseMessage::Revoked => error(ErrorCode::SessionRevoked, "The session was revoked"),
+        DatabaseMessage::MissingScope => {
+            error(ErrorCode::Forbidden, "The API key does not allow this")
+        }
+        _ => error(code, message),
+    }
+}
+
+fn status(code: ErrorCode) -> u16 {
+    match code {
+        ErrorCode::BadRequest => 400,
+        ErrorCode::Unauthorized | ErrorCode::TokenExpired | ErrorCode::SessionRevoked => 401,
+        ErrorCode::Forbidden => 403,
+        ErrorCode::NotFound => 404,
+        ErrorCode::Conflict => 409,
+        ErrorCode::Upstream => 502,
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        409 => "Conflict",
+        _ => "Bad Gateway",
+    }
+}
+
+// Reads the head and then as much body as `Content-Length` announces.
+fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    stream.set_read_timeout(Some(READ_TIMEOUT)).ok()?;
+    let mut data = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let n = stream.read(&mut buffer).ok()?;
+        if n == 0 || data.len() + n > MAX_REQUEST_SIZE {
+            return None;
+        }
+        data.extend_from_slice(&buffer[..n]);
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Request::new(&mut headers);
+        let httparse::Status::Complete(start) = parsed.parse(&data).ok()? else {
+            continue;
+        };
+        let length = match get_header(parsed.headers, "Content-Length") {
+            Some(length) => length.trim().parse::<usize>().ok()?,
+            None => 0,
+        };
+        if start + length > MAX_REQUEST_SIZE {
+            return None;
+        }
+        let (path, query) = match parsed.path?.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (parsed.path?, ""),
+        };
+        let query = query
+            .split('&')
+            .filter_map(|x| x.split_once('='))
+            .map(|(key, value)| Some((percent_decode(key)?, percent_decode(value)?)))
+            .collect::<Option<_>>()?;
+        let mut request = Request {
+            method: parsed.method?.to_string(),
+            path: path.to_string(),
+            query,
+            authorization: get_header(parsed.headers, "Authorization"),
+            body: data[start..].to_vec(),
+        };
+        while request.body.len() < length {
+            let n = stream.read(&mut buffer).ok()?;
+            if n == 0 {
+                return None;
+            }
+            request.body.extend_from_slice(&buffer[..n]);
+        }
+        request.body.truncate(length);
+        return Some(request);
+    }
+}
+
+// Decodes a query component, where `+` stands for a space. Escapes that do
+// not decode to UTF-8 make the request malformed.
+fn percent_decode(input: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut input = input.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'%' => {
+                let high = char::from(input.next()?).to_digit(16)?;
+                let low = char::from(input.next()?).to_digit(16)?;
+                bytes.push((high * 16 + low) as u8);
+            }
+            b'+' => bytes.push(b' '),
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_components_are_percent_decoded() {
+        assert_eq!(percent_decode("a%2Fb%3D%3D").as_deref(), Some("a/b=="));
+        assert_eq!(percent_decode("new+chat").as_deref(), Some("new chat"));
+        assert_eq!(percent_decode("%C3%A9").as_deref(), Some("é"));
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%FF"), None);
+    }
+
+    #[test]
+    fn completion_requests_take_both_openai_shapes() {
+        let request = r#"{"messages": [
+            {"role": "system", "content": "Be brief."},
+            {"role": "user", "content": [{"type": "text", "text": "a"}, {"type": "text", "text": "b"}]}
+        ], "stop": "END"}"#;
+        let request = serde_json::from_str::<CompletionRequest>(request).unwrap();
+        assert_eq!(request.sampling.stop, Some(vec!["END".to_string()]));
+        let messages: Vec<Message> = request
+            .messages
+            .into_iter()
+            .map(|x| x.flatten().unwrap())
+            .collect();
+        assert_eq!(messages[0].content.as_deref(), Some("Be brief."));
+        assert_eq!(messages[1].content.as_deref(), Some("a\nb"));
+
+        let request = r#"{"messages": [
+            {"role": "user", "content": [{"type": "image_url", "image_url": {"url": "x"}}]}
+        ], "stop": ["a", "b"]}"#;
+        let request = serde_json::from_str::<CompletionRequest>(request).unwrap();
+        assert_eq!(request.sampling.stop.map(|x| x.len()), Some(2));
+        let message = request.messages.into_iter().next().unwrap();
+        assert!(message.flatten().is_err());
+    }
+}
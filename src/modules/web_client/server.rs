@@ -1,55 +1,90 @@
 use crate::modules::database::types::*;
-use crate::modules::web_client::{client::WebClient, http::*, types::*};
-use serde_json::json;
-use sha2::Digest;
+use crate::modules::env::env::Env;
+use crate::modules::password::password::{self, Verification};
+use crate::modules::web_client::{client::WebClient, protocol, types::*};
+use base64::Engine;
 use std::{
+    collections::VecDeque,
     io::{ErrorKind, Read, Write},
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
+    },
 };
 
 use websocket::{
+    result::WebSocketError,
     stream::sync::TcpStream,
     sync::{Client, Reader, Server, Writer},
     OwnedMessage,
 };
 
-pub struct WebServer {
+pub const DEFAULT_PAGE_SIZE: u64 = 50;
+pub const MAX_PAGE_SIZE: u64 = 200;
+
+// Registers connections with the database, which answers every id with the
+// channels of that connection. Shared between the servers so that their
+// registrations never interleave.
+#[derive(Clone)]
+pub struct Registrar {
     sender: Sender<String>,
+    receiver: Arc<Mutex<Receiver<Channels>>>,
+}
+
+impl Registrar {
+    pub fn new(sender: Sender<String>, receiver: Receiver<Channels>) -> Self {
+        Self {
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+        }
+    }
+
+    pub fn register(&self, id: &str) -> Channels {
+        let receiver = self.receiver.lock().unwrap();
+        let _ = self.sender.send(id.to_string());
+        receiver.recv().unwrap()
+    }
+}
+
+pub struct WebServer {
+    registrar: Registrar,
     network_sender: Sender<NetworkMessage>,
-    receiver: Receiver<Receiver<DatabaseMessage>>,
 }
 
 impl WebServer {
-    pub fn new(
-        sender: Sender<String>,
-        network_sender: Sender<NetworkMessage>,
-        receiver: Receiver<Receiver<DatabaseMessage>>,
-    ) -> Self {
+    pub fn new(registrar: Registrar, network_sender: Sender<NetworkMessage>) -> Self {
         Self {
-            sender,
+            registrar,
             network_sender,
-            receiver,
         }
     }
 
     pub fn update(&mut self) {
         let mut listener = Server::bind("0.0.0.0:8080").expect("Unable to start WebSockets server");
-        while let Ok(stream) = listener.accept() {
+        while let Ok(mut stream) = listener.accept() {
+            let Some(version) = protocol::negotiate(stream.protocols()) else {
+                let _ = stream.reject();
+                continue;
+            };
+            if !stream.protocols().is_empty() {
+                stream = stream.use_protocol(protocol::subprotocol(version));
+            }
             if let Ok(client) = stream.accept() {
                 client.set_nonblocking(true).unwrap();
                 let (reader, writer) = client.split().unwrap();
                 println!("SUCCESS");
                 let addr = uuid::Uuid::new_v4().to_string();
-                let _ = self.sender.send(addr.to_string());
-                let receiver = self.receiver.recv().unwrap();
+                let channels = self.registrar.register(&addr);
                 let mut web_connection = WebConnection::new(
+                    reader,
                     writer,
                     addr.to_string(),
                     self.network_sender.clone(),
-                    receiver,
+                    channels,
+                    version,
                 );
                 std::thread::spawn(move || {
-                    web_connection.update(reader);
+                    web_connection.update();
                 });
             }
         }
@@ -57,131 +92,492 @@ impl WebServer {
 }
 
 pub struct WebConnection {
+    reader: Reader<TcpStream>,
     writer: Writer<TcpStream>,
     addr: String,
     sender: Sender<NetworkMessage>,
     receiver: Receiver<DatabaseMessage>,
+    // What other devices of the user change, forwarded between requests.
+    pushes: Receiver<DatabaseMessage>,
     web_client: WebClient,
+    // Frames read while a generation was streaming, handled once it is over.
+    pending: VecDeque<OwnedMessage>,
+    // A voice message whose recording comes in the next binary frame.
+    awaiting_voice: Option<VoiceMessage>,
+    // Whether the connection is bound to a session, see `authenticate`.
+    authenticated: bool,
+    // Set once the client went away or its session was revoked, which ends
+    // the connection.
+    closed: bool,
+    // The `request_id` of the frame being handled, echoed on its responses.
+    request_id: Option<String>,
+    // The protocol version negotiated when the connection was opened.
+    version: u32,
 }
 
 impl WebConnection {
     pub fn new(
+        reader: Reader<TcpStream>,
         writer: Writer<TcpStream>,
         addr: String,
         sender: Sender<NetworkMessage>,
-        receiver: Receiver<DatabaseMessage>,
+        channels: Channels,
+        version: u32,
     ) -> Self {
         let web_client = WebClient::new();
         Self {
+            reader,
             writer,
             addr,
             sender,
-            receiver,
+            receiver: channels.replies,
+            pushes: channels.pushes,
             web_client,
+            pending: VecDeque::new(),
+            awaiting_voice: None,
+            authenticated: false,
+            closed: false,
+            request_id: None,
+            version,
         }
     }
 
-    pub fn update(&mut self, mut reader: Reader<TcpStream>) {
-        loop {
+    pub fn update(&mut self) {
+        while !self.closed {
             self.receive_messages();
-            if let Ok(message) = reader.recv_message() {
-                if let OwnedMessage::Text(data) = message {
+            let message = match self.pending.pop_front() {
+                Some(message) => message,
+                None => match self.reader.recv_message() {
+                    Ok(message) => message,
+                    Err(error) => {
+                        self.closed = disconnected(&error);
+                        continue;
+                    }
+                },
+            };
+            match message {
+                OwnedMessage::Text(data) => {
                     let mut headers = [httparse::EMPTY_HEADER; 64];
                     let req = httparse::Request::new(&mut headers);
                     self.handle_request(&req, &data);
+                    self.request_id = None;
                 }
+                OwnedMessage::Binary(audio) => self.receive_voice(audio),
+                OwnedMessage::Close(_) => {
+                    let _ = self.writer.send_message(&OwnedMessage::Close(None));
+                    self.closed = true;
+                }
+                _ => {}
             }
         }
+        let _ = self
+            .sender
+            .send(NetworkMessage::Disconnect(self.addr.clone()));
     }
 
     fn receive_messages(&mut self) {
-        while let Ok(message) = self.receiver.try_recv() {
+        while let Ok(message) = self.pushes.try_recv() {
             match message {
                 DatabaseMessage::WebMessage(message) => {
-                    let message = json!(ServerResponse::Message(message)).to_string();
-                    let _ = self.writer.send_message(&OwnedMessage::Text(message));
+                    self.send(ServerResponse::Message(message));
                 }
                 DatabaseMessage::Deleted(chat_id) => {
-                    let message = json!(ServerResponse::Deleted(chat_id)).to_string();
-                    let _ = self.writer.send_message(&OwnedMessage::Text(message));
+                    self.send(ServerResponse::Deleted(chat_id));
                 }
                 DatabaseMessage::NewChat(chat_id) => {
-                    let message = json!(ServerResponse::ChatId(chat_id)).to_string();
-                    let _ = self.writer.send_message(&OwnedMessage::Text(message));
+                    self.send(ServerResponse::ChatId(chat_id));
+                }
+                DatabaseMessage::ChatTitle(title) => {
+                    self.send(ServerResponse::ChatTitle(title));
                 }
-                message => todo!("{:?}", message),
+                DatabaseMessage::Revoked => self.revoked(),
+                _ => {}
             }
         }
     }
 
     fn handle_request(&mut self, request: &httparse::Request, data: &str) {
-        if let Ok(message) = serde_json::from_str::<ClientMessage>(data) {
-            match message.body {
-                ClientMessageKind::Login(login) => {
-                    self.login(login);
+        let message = match protocol::parse(self.version, data) {
+            Ok(message) => message,
+            Err(error) => {
+                // Still answer to the id of frames that parse as far as that.
+                self.request_id = serde_json::from_str::<serde_json::Value>(data)
+                    .ok()
+                    .and_then(|x| x.get("request_id")?.as_str().map(|x| x.to_string()));
+                self.error(ErrorCode::BadRequest, &error.to_string());
+                return;
+            }
+        };
+        self.request_id = message.request_id.clone();
+        if let Some(token) = message.body.token().filter(|_| !self.authenticated) {
+            if !self.authenticate(token, false) {
+                return;
+            }
+        }
+        if !self.authenticated && message.body.requires_authentication() {
+            self.error(ErrorCode::Unauthorized, "Not authenticated");
+            return;
+        }
+        match message.body {
+            ClientMessageKind::Authenticate(authenticate) => {
+                self.authenticate(&authenticate.token, true);
+            }
+            ClientMessageKind::Login(login) => {
+                self.login(login);
+            }
+            ClientMessageKind::NewChat(new_chat) => {
+                self.new_chat(new_chat);
+            }
+            ClientMessageKind::DeleteChat(delete_chat) => {
+                self.delete_chat(delete_chat);
+            }
+            ClientMessageKind::NewMessage(new_message) => {
+                self.send_message(new_message);
+            }
+            ClientMessageKind::GetChats(_) => self.get_chats(),
+            ClientMessageKind::GetChat(get_chat) => self.get_chat(get_chat),
+            ClientMessageKind::Register(register) => self.register_user(register),
+            ClientMessageKind::GetAudio(get_audio) => self.get_audio(get_audio),
+            // Only meaningful while a generation is streaming, see `cancel_requested`.
+            ClientMessageKind::Cancel(_) => {
+                self.error(ErrorCode::NotFound, "Nothing is being generated")
+            }
+            ClientMessageKind::VoiceMessage(voice) => self.new_voice_message(voice),
+            ClientMessageKind::SetSystemPrompt(prompt) => self.set_system_prompt(prompt),
+            ClientMessageKind::SetChatSettings(settings) => self.set_chat_settings(settings),
+            ClientMessageKind::Search(search) => self.search(search),
+            ClientMessageKind::EditMessage(edit) => {
+                self.edit_message(edit);
+            }
+            ClientMessageKind::SwitchBranch(switch) => self.switch_branch(switch),
+            ClientMessageKind::Regenerate(regenerate) => {
+                self.regenerate(regenerate);
+            }
+            ClientMessageKind::GetSessions(_) => {
+                let _ = self
+                    .sender
+                    .send(NetworkMessage::GetSessions(self.addr.clone()));
+                self.send_sessions();
+            }
+            ClientMessageKind::RevokeSession(revoke) => {
+                let _ = self.sender.send(NetworkMessage::RevokeSession(
+                    self.addr.clone(),
+                    revoke.session_id,
+                ));
+                self.send_sessions();
+            }
+            ClientMessageKind::Refresh(refresh) => self.refresh(refresh),
+            ClientMessageKind::CreateApiKey(create) => self.create_api_key(create),
+            ClientMessageKind::GetApiKeys(_) => {
+                let _ = self
+                    .sender
+                    .send(NetworkMessage::GetApiKeys(self.addr.clone()));
+                self.send_api_keys();
+            }
+            ClientMessageKind::RevokeApiKey(revoke) => {
+                let _ = self.sender.send(NetworkMessage::RevokeApiKey(
+                    self.addr.clone(),
+                    revoke.key_id,
+                ));
+                self.send_api_keys();
+            }
+            ClientMessageKind::LogoutAll(_) => {
+                let _ = self
+                    .sender
+                    .send(NetworkMessage::LogoutAll(self.addr.clone()));
+                self.send_sessions();
+            }
+            ClientMessageKind::RenameChat(rename) => {
+                let title = rename.title.trim();
+                if title.is_empty() || title.chars().count() > 100 {
+                    self.error(ErrorCode::BadRequest, "Titles take 1 to 100 characters");
+                    return;
                 }
-                ClientMessageKind::NewChat(new_chat) => {
-                    self.new_chat(new_chat);
+                let title = ChatTitle {
+                    chat_id: rename.chat_id,
+                    title: title.to_string(),
+                };
+                self.rename_chat(title, false);
+            }
+            ClientMessageKind::GetChatSettings(get_chat) => {
+                match self.retrieve_chat_settings(&get_chat.chat_id) {
+                    DatabaseMessage::ChatSettings(settings) => {
+                        self.send(ServerResponse::ChatSettings(settings));
+                    }
+                    response => self.deny(response, ErrorCode::Forbidden, "No such chat"),
                 }
-                ClientMessageKind::DeleteChat(delete_chat) => {
-                    self.delete_chat(delete_chat);
+            }
+            ClientMessageKind::GetModels(_) => {
+                let models = Env::new().allowed_models();
+                self.send(ServerResponse::Models(models));
+            }
+            ClientMessageKind::GetPersonas(_) => {
+                let _ = self
+                    .sender
+                    .send(NetworkMessage::GetPersonas(self.addr.clone()));
+                self.send_personas();
+            }
+            ClientMessageKind::SavePersona(save) => {
+                if save.name.trim().is_empty() {
+                    self.error(ErrorCode::BadRequest, "Personas need a name");
+                    return;
                 }
-                ClientMessageKind::NewMessage(new_message) => self.send_message(new_message),
-                ClientMessageKind::GetChats(ref token) => self.get_chats(token),
-                ClientMessageKind::GetChat(get_chat) => self.get_chat(get_chat),
-                ClientMessageKind::Register(register) => self.register_user(register),
-                ClientMessageKind::GetAudio(get_audio) => self.get_audio(get_audio),
+                let _ = self.sender.send(NetworkMessage::SavePersona(
+                    self.addr.clone(),
+                    Persona::new(save.name.trim().to_string(), save.prompt),
+                ));
+                self.send_personas();
+            }
+            ClientMessageKind::DeletePersona(delete) => {
+                let _ = self.sender.send(NetworkMessage::DeletePersona(
+                    self.addr.clone(),
+                    delete.name,
+                ));
+                self.send_personas();
             }
         }
     }
 
-    fn get_audio(&mut self, get_audio: GetAudio) {
-        let _ = self.sender.send(NetworkMessage::TokenValidation(
+    fn search(&mut self, search: Search) {
+        if search.query.trim().is_empty() {
+            self.error(ErrorCode::BadRequest, "The query is empty");
+            return;
+        }
+        let limit = search
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let _ = self.sender.send(NetworkMessage::Search(
             self.addr.clone(),
-            get_audio.token,
+            search.query.clone(),
+            limit,
         ));
-        let response = self.receiver.recv().unwrap();
+        let response = reply(&self.receiver);
         match response {
-            DatabaseMessage::Email(ref _email) => {
-                let _ = self.sender.send(NetworkMessage::GetMessage(
-                    self.addr.clone(),
-                    get_audio.message_id.to_string(),
-                ));
-                let response = self.receiver.recv().unwrap();
-                match response {
-                    DatabaseMessage::Message(ref message) => {
-                        let message = message.content.as_ref().unwrap();
-                        let data = self.get_audio_file(get_audio.message_id.to_string(), message);
-                        let response = ServerResponse::Audio(AudioInfo {
-                            message_id: get_audio.message_id.to_string(),
-                            content: data,
-                        });
-                        let response = json!(response).to_string();
-                        let _ = self.writer.send_message(&OwnedMessage::Text(response));
-                    }
-                    _ => self.generic_error(403, "Forbidden"),
-                }
+            DatabaseMessage::SearchResults(results) => {
+                self.send(ServerResponse::SearchResults(SearchResults {
+                    query: search.query,
+                    results,
+                }));
+            }
+            response => self.deny(response, ErrorCode::Unauthorized, "Not authenticated"),
+        }
+    }
+
+    fn rename_chat(&mut self, title: ChatTitle, generated: bool) {
+        let _ = self.sender.send(NetworkMessage::RenameChat(
+            self.addr.clone(),
+            title,
+            generated,
+        ));
+        let response = reply(&self.receiver);
+        match response {
+            DatabaseMessage::ChatTitle(title) => {
+                self.send(ServerResponse::ChatTitle(title));
+            }
+            // A generated title losing to one the user set is not an error.
+            _ if generated => {}
+            response => self.deny(response, ErrorCode::Forbidden, "No such chat"),
+        }
+    }
+
+    fn set_chat_settings(&mut self, settings: SetChatSettings) {
+        let env = Env::new();
+        let allowed = settings
+            .model
+            .as_ref()
+            .is_none_or(|x| env.allowed_models().contains(x));
+        if !allowed || settings.sampling.validate().is_err() {
+            self.error(ErrorCode::BadRequest, "Invalid model or sampling settings");
+            return;
+        }
+        let chat_settings = ChatSettings {
+            chat_id: settings.chat_id,
+            model: settings.model,
+            sampling: settings.sampling,
+        };
+        let _ = self.sender.send(NetworkMessage::SetChatSettings(
+            self.addr.clone(),
+            chat_settings,
+        ));
+        let response = reply(&self.receiver);
+        match response {
+            DatabaseMessage::ChatSettings(chat_settings) => {
+                self.send(ServerResponse::ChatSettings(chat_settings));
+            }
+            response => self.deny(response, ErrorCode::Forbidden, "No such chat"),
+        }
+    }
+
+    fn set_system_prompt(&mut self, prompt: SetSystemPrompt) {
+        let chat_prompt = ChatPrompt {
+            chat_id: prompt.chat_id,
+            system_prompt: prompt.system_prompt,
+            persona: prompt.persona,
+        };
+        let _ = self.sender.send(NetworkMessage::SetSystemPrompt(
+            self.addr.clone(),
+            chat_prompt,
+        ));
+        let response = reply(&self.receiver);
+        match response {
+            DatabaseMessage::ChatPrompt(chat_prompt) => {
+                self.send(ServerResponse::SystemPrompt(chat_prompt));
+            }
+            response => self.deny(response, ErrorCode::Forbidden, "No such chat"),
+        }
+    }
+
+    fn send_personas(&mut self) {
+        let response = reply(&self.receiver);
+        match response {
+            DatabaseMessage::Personas(personas) => {
+                self.send(ServerResponse::Personas(personas));
+            }
+            response => self.deny(response, ErrorCode::Unauthorized, "Not authenticated"),
+        }
+    }
+
+    fn refresh(&mut self, refresh: Refresh) {
+        let _ = self.sender.send(NetworkMessage::Refresh(
+            self.addr.clone(),
+            refresh.refresh_token,
+        ));
+        let response = reply(&self.receiver);
+        match response {
+            DatabaseMessage::Token(credentials) => {
+                self.authenticated = true;
+                self.send(ServerResponse::Credentials(credentials));
             }
-            _ => self.generic_error(401, "Unauthorized"),
+            _ => self.error(ErrorCode::Unauthorized, "Invalid refresh token"),
         }
     }
 
-    fn get_audio_file(&mut self, id: String, message: &str) -> String {
+    fn send_sessions(&mut self) {
+        let response = reply(&self.receiver);
+        match response {
+            DatabaseMessage::Sessions(sessions) => {
+                self.send(ServerResponse::Sessions(sessions));
+            }
+            response => self.deny(response, ErrorCode::Unauthorized, "Not authenticated"),
+        }
+    }
+
+    fn create_api_key(&mut self, create: CreateApiKey) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if let Err(message) = create.validate(now) {
+            self.error(ErrorCode::BadRequest, message);
+            return;
+        }
         let _ = self
             .sender
-            .send(NetworkMessage::GetAudioPath(self.addr.clone(), id.clone()));
-        let response = self.receiver.recv().unwrap();
+            .send(NetworkMessage::CreateApiKey(self.addr.clone(), create));
+        let response = reply(&self.receiver);
         match response {
-            DatabaseMessage::AudioPath(ref path) => std::fs::read_to_string(path).unwrap(),
-            _ => {
-                let audio = self.web_client.new_audio(message.to_string()).unwrap();
-                let path = format!("static/{}", &id);
-                let _ = self
-                    .sender
-                    .send(NetworkMessage::RecordAudioPath(id, path.clone()));
-                std::fs::write(path, &audio).unwrap();
-                audio
+            DatabaseMessage::ApiKeyCreated(key) => {
+                self.send(ServerResponse::ApiKeyCreated(key));
+            }
+            response => self.deny(response, ErrorCode::Unauthorized, "Not authenticated"),
+        }
+    }
+
+    fn send_api_keys(&mut self) {
+        let response = reply(&self.receiver);
+        match response {
+            DatabaseMessage::ApiKeys(keys) => {
+                self.send(ServerResponse::ApiKeys(keys));
+            }
+            response => self.deny(response, ErrorCode::Unauthorized, "Not authenticated"),
+        }
+    }
+
+    fn new_voice_message(&mut self, mut voice: VoiceMessage) {
+        if let Err(message) = voice.format() {
+            self.error(ErrorCode::BadRequest, message);
+            return;
+        }
+        match voice.audio.take() {
+            Some(encoded) => match base64::engine::general_purpose::STANDARD.decode(encoded) {
+                Ok(audio) => self.voice_message(voice, audio),
+                Err(_) => self.error(ErrorCode::BadRequest, "The audio is not valid base64"),
+            },
+            None => self.awaiting_voice = Some(voice),
+        }
+    }
+
+    fn receive_voice(&mut self, audio: Vec<u8>) {
+        match self.awaiting_voice.take() {
+            Some(voice) => self.voice_message(voice, audio),
+            None => self.error(ErrorCode::BadRequest, "No voice message was announced"),
+        }
+    }
+
+    fn voice_message(&mut self, voice: VoiceMessage, audio: Vec<u8>) {
+        // Transcription is paid for, so strangers are turned away up front.
+        if !self.authenticated {
+            self.error(ErrorCode::Unauthorized, "Not authenticated");
+            return;
+        }
+        let Ok(format) = voice.format() else {
+            self.error(ErrorCode::BadRequest, "Unsupported audio format");
+            return;
+        };
+        let Some(transcript) = self.web_client.transcribe(&audio, format) else {
+            self.error(ErrorCode::Upstream, "Transcription failed");
+            return;
+        };
+        self.send(ServerResponse::Transcript(Transcript {
+            chat_id: voice.chat_id.clone(),
+            content: transcript.clone(),
+        }));
+        let answer = self.send_message(NewMessage {
+            token: voice.token,
+            chat_id: voice.chat_id,
+            content: transcript,
+        });
+        if !voice.speak {
+            return;
+        }
+        let Some(answer) = answer else {
+            return;
+        };
+        let content = answer.message.content.unwrap_or_default();
+        let audio = synthesize_audio(
+            &mut self.web_client,
+            &self.sender,
+            &self.addr,
+            &answer.id,
+            &content,
+        );
+        match audio {
+            Some(data) => {
+                self.send(ServerResponse::Audio(AudioInfo {
+                    message_id: answer.id,
+                    content: data,
+                }));
             }
+            None => self.error(ErrorCode::Upstream, "Speech synthesis failed"),
+        }
+    }
+
+    fn get_audio(&mut self, get_audio: GetAudio) {
+        let audio = audio_file(
+            &mut self.web_client,
+            &self.sender,
+            &self.receiver,
+            &self.addr,
+            &get_audio.message_id,
+        );
+        match audio {
+            Ok(Some(data)) => self.send(ServerResponse::Audio(AudioInfo {
+                message_id: get_audio.message_id.to_string(),
+                content: data,
+            })),
+            Ok(None) => self.error(ErrorCode::Upstream, "Speech synthesis failed"),
+            Err(response) => self.deny(*response, ErrorCode::Forbidden, "No such message"),
         }
     }
 
@@ -190,208 +586,567 @@ impl WebConnection {
             self.addr.clone(),
             register.name.to_string(),
             register.email.to_string(),
-            register.password.to_string(),
+            password::hash(&register.password),
+            register.device.clone(),
         ));
-        let response = self.receiver.recv().unwrap();
+        let response = reply(&self.receiver);
         match response {
-            DatabaseMessage::Token(ref token) => {
+            DatabaseMessage::Token(credentials) => {
+                self.authenticated = true;
                 let info = UserInfo::new(
                     register.email.to_string(),
                     register.name.to_string(),
-                    token.to_string(),
+                    credentials,
                 );
-                let response = ServerResponse::UserInfo(info);
-                let response = json!(response).to_string();
-                let _ = self.writer.send_message(&OwnedMessage::Text(response));
+                self.send(ServerResponse::UserInfo(info));
             }
-            _ => self.generic_error(401, "Unauthorized"),
+            response => self.deny(
+                response,
+                ErrorCode::Conflict,
+                "The email is already registered",
+            ),
         }
     }
 
     fn delete_chat(&mut self, delete_chat: DeleteChat) {
         let _ = self.sender.send(NetworkMessage::DeleteChat(
             self.addr.clone(),
-            delete_chat.token,
             delete_chat.chat_id,
         ));
-        let response = self.receiver.recv().unwrap();
+        let response = reply(&self.receiver);
         match response {
             DatabaseMessage::Deleted(chat_id) => {
-                let response = ServerResponse::Deleted(chat_id);
-                let response = json!(response).to_string();
-                let _ = self.writer.send_message(&OwnedMessage::Text(response));
+                self.send(ServerResponse::Deleted(chat_id));
+            }
+            response => self.deny(response, ErrorCode::NotFound, "No such chat"),
+        }
+    }
+
+    fn get_chats(&mut self) {
+        let _ = self
+            .sender
+            .send(NetworkMessage::GetChats(self.addr.clone()));
+        let response = reply(&self.receiver);
+        match response {
+            DatabaseMessage::Chats(chats) => {
+                self.send(ServerResponse::Chats(chats));
             }
-            _ => self.generic_error(404, "Not Found"),
+            response => self.deny(response, ErrorCode::Unauthorized, "Not authenticated"),
         }
     }
 
-    fn get_chats(&mut self, token: &str) {
-        let _ = self.sender.send(NetworkMessage::TokenValidation(
+    // Binds the connection to the session or API key of `token`, after which
+    // requests no longer need one. Only an explicit `authenticate` is acknowledged.
+    fn authenticate(&mut self, token: &str, acknowledge: bool) -> bool {
+        let _ = self.sender.send(NetworkMessage::Authenticate(
             self.addr.clone(),
             token.to_string(),
         ));
-        let response = self.receiver.recv().unwrap();
+        let response = reply(&self.receiver);
         match response {
             DatabaseMessage::Email(email) => {
-                let message = NetworkMessage::GetChats(self.addr.clone(), email);
-                let _ = self.sender.send(message);
-                let response = self.receiver.recv().unwrap();
-                match response {
-                    DatabaseMessage::Chats(chats) => {
-                        let response = ServerResponse::Chats(chats);
-                        let response = json!(response).to_string();
-                        let _ = self.writer.send_message(&OwnedMessage::Text(response));
-                    }
-                    _ => self.generic_error(500, "Internal Server Error"),
+                self.authenticated = true;
+                if acknowledge {
+                    self.send(ServerResponse::Authenticated(email));
                 }
+                true
+            }
+            response => {
+                self.deny(response, ErrorCode::Unauthorized, "Invalid access token");
+                false
             }
-            _ => self.generic_error(401, "Unauthorized"),
         }
     }
 
+    // Hashing runs on this connection's thread so slow hashes never hold up
+    // the database for everyone else.
     fn login(&mut self, login: Login) {
-        let mut hasher = sha2::Sha256::new();
-        hasher.update(login.password.trim());
-        let finalized = &hasher.finalize();
-        let password_hash = hex::encode(finalized);
-
+        let _ = self.sender.send(NetworkMessage::GetPasswordHash(
+            self.addr.clone(),
+            login.email.clone(),
+        ));
+        let DatabaseMessage::PasswordHash(stored) = reply(&self.receiver) else {
+            password::verify_unknown(&login.password);
+            self.error(ErrorCode::Unauthorized, "Wrong email or password");
+            return;
+        };
+        let rehash = match password::verify(&login.password, &stored) {
+            Verification::Valid => None,
+            Verification::Outdated => Some(password::hash(&login.password)),
+            Verification::Invalid => {
+                self.error(ErrorCode::Unauthorized, "Wrong email or password");
+                return;
+            }
+        };
         let _ = self.sender.send(NetworkMessage::LoginRequest(
             self.addr.clone(),
             login.email,
-            password_hash.to_string(),
+            rehash,
+            login.device,
         ));
-        let response = self.receiver.recv().unwrap();
+        let response = reply(&self.receiver);
         match response {
             DatabaseMessage::UserInfo(info) => {
-                let response = ServerResponse::UserInfo(info);
-                let response = json!(response).to_string();
-                let _ = self.writer.send_message(&OwnedMessage::Text(response));
+                self.authenticated = true;
+                self.send(ServerResponse::UserInfo(info));
             }
-            _ => self.generic_error(401, "Unauthorized"),
+            response => self.deny(response, ErrorCode::Unauthorized, "Wrong email or password"),
         }
     }
 
-    fn new_chat(&mut self, new_chat: NewChat) {
-        let _ = self.sender.send(NetworkMessage::TokenValidation(
-            self.addr.clone(),
-            new_chat.token,
-        ));
-        let response = self.receiver.recv().unwrap();
+    fn new_chat(&mut self, _new_chat: NewChat) {
+        let _ = self.sender.send(NetworkMessage::NewChat(self.addr.clone()));
+        let response = reply(&self.receiver);
         match response {
-            DatabaseMessage::Email(email) => {
-                let message = NetworkMessage::NewChat(self.addr.clone(), email);
-                let _ = self.sender.send(message);
-                let response = self.receiver.recv().unwrap();
-                match response {
-                    DatabaseMessage::NewChat(id) => {
-                        let response = ServerResponse::ChatId(id);
-                        let response = json!(response).to_string();
-                        let _ = self.writer.send_message(&OwnedMessage::Text(response));
-                    }
-                    _ => self.generic_error(403, "Forbidden"),
-                }
+            DatabaseMessage::NewChat(id) => {
+                self.send(ServerResponse::ChatId(id));
+            }
+            response => self.deny(response, ErrorCode::Unauthorized, "Not authenticated"),
+        }
+    }
+
+    // Like `error`, unless the request failed because the access token
+    // expired, which clients answer by refreshing it, or was out of the
+    // scopes of the API key the connection uses.
+    fn deny(&mut self, response: DatabaseMessage, code: ErrorCode, message: &str) {
+        match response {
+            DatabaseMessage::TokenExpired => {
+                self.error(ErrorCode::TokenExpired, "The access token expired")
             }
-            _ => self.generic_error(401, "Unauthorized"),
+            DatabaseMessage::Revoked => self.revoked(),
+            DatabaseMessage::MissingScope => {
+                self.error(ErrorCode::Forbidden, "The API key does not allow this")
+            }
+            _ => self.error(code, message),
         }
     }
 
-    fn generic_error(&mut self, code: u16, reason: &str) {
-        let headers = vec![("Access-Control-Allow-Origin", "*")];
-        let mut headers = new_headers(&headers);
-        let mut response = httparse::Response::new(&mut headers);
-        response.code = Some(code);
-        response.reason = Some(reason);
-        let response = response_to_string(response, None::<String>);
+    // Ends the live session of a device whose session was revoked.
+    fn revoked(&mut self) {
+        self.error(ErrorCode::SessionRevoked, "The session was revoked");
+        let _ = self.writer.send_message(&OwnedMessage::Close(None));
+        self.authenticated = false;
+        self.closed = true;
+    }
+
+    fn error(&mut self, code: ErrorCode, message: &str) {
+        self.send(ServerResponse::Error {
+            request_id: self.request_id.clone(),
+            code,
+            message: message.to_string(),
+            retryable: code.retryable(),
+        });
+    }
+
+    // Sends `response` as the answer to the request being handled.
+    fn send(&mut self, response: ServerResponse) {
+        let response = protocol::encode(self.version, response, self.request_id.as_deref());
         let _ = self.writer.send_message(&OwnedMessage::Text(response));
     }
 
     fn get_chat(&mut self, get_chat: GetChat) {
-        let _ = self.sender.send(NetworkMessage::ChatRequest(
+        let page = PageRequest {
+            cursor: get_chat.cursor,
+            direction: get_chat.direction,
+            limit: get_chat
+                .limit
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .clamp(1, MAX_PAGE_SIZE),
+        };
+        let _ = self.sender.send(NetworkMessage::GetMessagePage(
             self.addr.clone(),
-            get_chat.token,
             get_chat.chat_id.to_string(),
+            page,
         ));
-        let response = self.receiver.recv().unwrap();
+        let response = reply(&self.receiver);
 
         match response {
-            DatabaseMessage::Messages(ref _id, messages) => {
-                let messages = Messages::new(messages).messages;
-                let response = ServerResponse::Messages(messages);
-                let response = json!(response).to_string();
-                let _ = self.writer.send_message(&OwnedMessage::Text(response));
+            DatabaseMessage::MessagePage(page) => {
+                self.send(ServerResponse::Messages(page));
             }
-            _ => self.generic_error(403, "Forbidden"),
+            response => self.deny(response, ErrorCode::Forbidden, "No such chat"),
         }
     }
 
-    fn send_message(&mut self, new_message: NewMessage) {
-        if new_message.content.trim().len() < 1 {
-            self.generic_error(400, "Bad Request");
-            return;
+    fn send_message(&mut self, new_message: NewMessage) -> Option<WebMessage> {
+        if new_message.content.trim().is_empty() {
+            self.error(ErrorCode::BadRequest, "The message is empty");
+            return None;
         }
+        let message_id = uuid::Uuid::new_v4().to_string();
         let _ = self.sender.send(NetworkMessage::NewMessage(
             self.addr.clone(),
-            new_message.token.clone(),
             "user".to_string(),
             new_message.chat_id.to_string(),
             new_message.content.clone(),
-            uuid::Uuid::new_v4().to_string(),
+            message_id.clone(),
+            false,
+            None,
         ));
         let message = Message::new("user", &new_message.content);
-        let response = self.receiver.recv().unwrap();
+        let response = reply(&self.receiver);
         match response {
             DatabaseMessage::Timestamp(timestamp) => {
-                if &self.web_client.chat_id != &new_message.chat_id {
-                    if let Some(messages) =
-                        self.retrieve_messages(&new_message.token, &new_message.chat_id)
-                    {
-                        self.web_client.load_context(messages);
-                        self.web_client.chat_id = new_message.chat_id.clone();
-                    }
+                let message = WebMessage::new(message, timestamp, message_id);
+                self.answer(&new_message, message, Vec::new())
+            }
+            response => {
+                self.deny(response, ErrorCode::Forbidden, "No such chat");
+                None
+            }
+        }
+    }
+
+    fn edit_message(&mut self, edit: EditMessage) -> Option<WebMessage> {
+        if edit.content.trim().is_empty() {
+            self.error(ErrorCode::BadRequest, "The message is empty");
+            return None;
+        }
+        let _ = self.sender.send(NetworkMessage::EditMessage(
+            self.addr.clone(),
+            edit.chat_id.clone(),
+            edit.message_id,
+            uuid::Uuid::new_v4().to_string(),
+            edit.content.clone(),
+        ));
+        let response = reply(&self.receiver);
+        match response {
+            DatabaseMessage::WebMessage(message) => {
+                self.send(ServerResponse::Message(message.clone()));
+                // The loaded context still follows the old branch.
+                self.web_client.chat_id = String::new();
+                let request = NewMessage {
+                    token: edit.token,
+                    chat_id: edit.chat_id,
+                    content: edit.content,
+                };
+                self.answer(&request, message, Vec::new())
+            }
+            response => {
+                self.deny(response, ErrorCode::Forbidden, "No such message");
+                None
+            }
+        }
+    }
+
+    fn switch_branch(&mut self, switch: SwitchBranch) {
+        let _ = self.sender.send(NetworkMessage::SwitchBranch(
+            self.addr.clone(),
+            switch.chat_id.clone(),
+            switch.message_id,
+        ));
+        let response = reply(&self.receiver);
+        match response {
+            DatabaseMessage::Ok => {
+                if self.web_client.chat_id == switch.chat_id {
+                    self.web_client.chat_id = String::new();
                 }
-                let id = uuid::Uuid::new_v4().to_string();
-                let message = WebMessage::new(message, timestamp, String::new());
-                let mut copy: Message = Message::new("", "");
-                let _ = copy;
-                if let Some(answer) = self.web_client.new_message(message) {
-                    copy = answer.clone();
-                    let _ = self.sender.send(NetworkMessage::NewMessage(
-                        self.addr.clone(),
-                        new_message.token,
-                        answer.role.as_ref().unwrap().clone(),
-                        new_message.chat_id.to_string(),
-                        answer.content.as_ref().unwrap().clone(),
-                        id.clone(),
-                    ));
-                    self.receiver.recv().unwrap();
-                } else {
-                    self.generic_error(502, "Bad Gateway");
-                    return;
+                self.get_chat(GetChat {
+                    token: switch.token,
+                    chat_id: switch.chat_id,
+                    cursor: None,
+                    direction: Direction::Before,
+                    limit: None,
+                });
+            }
+            response => self.deny(response, ErrorCode::Forbidden, "No such message"),
+        }
+    }
+
+    fn regenerate(&mut self, regenerate: Regenerate) -> Option<WebMessage> {
+        let _ = self.sender.send(NetworkMessage::Regenerate(
+            self.addr.clone(),
+            regenerate.chat_id.clone(),
+        ));
+        let response = reply(&self.receiver);
+        match response {
+            DatabaseMessage::Rewound(message, answers, head) => {
+                // The loaded context ends with the answer being replaced.
+                self.web_client.chat_id = String::new();
+                let request = NewMessage {
+                    token: regenerate.token,
+                    chat_id: regenerate.chat_id,
+                    content: message.message.content.clone().unwrap_or_default(),
+                };
+                let answer = self.answer(&request, message, answers);
+                if answer.is_none() {
+                    // Keep the previous answer active when none replaced it.
+                    if let Some(head) = head {
+                        let _ = self.sender.send(NetworkMessage::SwitchBranch(
+                            self.addr.clone(),
+                            request.chat_id.clone(),
+                            head,
+                        ));
+                        reply(&self.receiver);
+                    }
                 }
-                let response = ServerResponse::Message(WebMessage::new(copy, timestamp, id));
-                let response = json!(response).to_string();
-                let _ = self.writer.send_message(&OwnedMessage::Text(response));
+                answer
+            }
+            response => {
+                self.deny(response, ErrorCode::Forbidden, "Nothing to regenerate");
+                None
+            }
+        }
+    }
+
+    // Streams the answer to `message`, already stored as the head of the chat.
+    // `answers` are the ones it already has, which the new one joins.
+    fn answer(
+        &mut self,
+        new_message: &NewMessage,
+        message: WebMessage,
+        mut answers: Vec<String>,
+    ) -> Option<WebMessage> {
+        let timestamp = message.created_at;
+        let parent_id = message.id.clone();
+        load_chat(
+            &mut self.web_client,
+            &self.sender,
+            &self.receiver,
+            &self.addr,
+            &new_message.chat_id,
+        );
+        let id = uuid::Uuid::new_v4().to_string();
+        let start = ServerResponse::MessageStart(StreamStart {
+            chat_id: new_message.chat_id.clone(),
+            message_id: id.clone(),
+            created_at: timestamp,
+        });
+        self.send(start);
+        let request_id = self.request_id.clone();
+        let version = self.version;
+        let reader = &mut self.reader;
+        let writer = &mut self.writer;
+        let pending = &mut self.pending;
+        let closed = &mut self.closed;
+        let answer = self.web_client.new_message(message, &id, |delta| {
+            let response = ServerResponse::MessageDelta(StreamDelta {
+                chat_id: new_message.chat_id.clone(),
+                message_id: id.clone(),
+                content: delta.to_string(),
+            });
+            let response = protocol::encode(version, response, request_id.as_deref());
+            let _ = writer.send_message(&OwnedMessage::Text(response));
+            !cancel_requested(reader, pending, closed, version, new_message)
+        });
+        if let Some(summary) = self.web_client.take_summary() {
+            let _ = self.sender.send(NetworkMessage::SaveSummary(
+                self.addr.clone(),
+                new_message.chat_id.clone(),
+                summary,
+            ));
+        }
+        let Some(answer) = answer else {
+            self.error(ErrorCode::Upstream, "The model did not answer");
+            return None;
+        };
+        let _ = self.sender.send(NetworkMessage::NewMessage(
+            self.addr.clone(),
+            answer.message.role.as_ref().unwrap().clone(),
+            new_message.chat_id.to_string(),
+            answer.message.content.as_ref().unwrap().clone(),
+            id.clone(),
+            answer.truncated,
+            Some(answer.model.clone()),
+        ));
+        // Tokens can expire while a long answer is generated.
+        match reply(&self.receiver) {
+            DatabaseMessage::Timestamp(_) => {}
+            response => {
+                // The loaded context holds an answer the chat does not.
+                self.web_client.chat_id = String::new();
+                self.deny(
+                    response,
+                    ErrorCode::Forbidden,
+                    "The answer could not be saved",
+                );
+                return None;
+            }
+        }
+        let mut done = WebMessage::new(answer.message, timestamp, id);
+        done.truncated = answer.truncated;
+        done.usage = Some(answer.usage);
+        done.model = Some(answer.model);
+        done.parent_id = Some(parent_id);
+        if !answers.is_empty() {
+            answers.push(done.id.clone());
+            done.siblings = answers;
+        }
+        self.send(ServerResponse::MessageDone(done.clone()));
+        if self.web_client.is_first_exchange() {
+            if let Some(title) = self.web_client.generate_title() {
+                let title = ChatTitle {
+                    chat_id: new_message.chat_id.clone(),
+                    title,
+                };
+                self.rename_chat(title, true);
             }
-            _ => self.generic_error(403, "Forbidden"),
         }
+        Some(done)
     }
 
     fn handle_invalid_endpoint(&mut self) {
-        self.generic_error(404, "Not Found");
+        self.error(ErrorCode::NotFound, "Unknown request");
     }
 
-    fn retrieve_messages(&self, token: &str, chat_id: &str) -> Option<Messages> {
-        let _ = self.sender.send(NetworkMessage::ChatRequest(
+    fn retrieve_chat_settings(&self, chat_id: &str) -> DatabaseMessage {
+        let _ = self.sender.send(NetworkMessage::GetChatSettings(
             self.addr.clone(),
-            token.to_string(),
             chat_id.to_string(),
         ));
-        let response = self.receiver.recv().unwrap();
-        match response {
-            DatabaseMessage::Messages(_id, messages) => Some(Messages::new(messages)),
-            _ => None,
+        reply(&self.receiver)
+    }
+}
+
+// Waits for the answer to the request just sent. A database thread that went
+// away answers nothing, which reads as a failed request.
+pub fn reply(receiver: &Receiver<DatabaseMessage>) -> DatabaseMessage {
+    receiver.recv().unwrap_or(DatabaseMessage::Err)
+}
+
+// Loads the chat `chat_id` into `web_client` unless it already holds it.
+// The system prompt and settings are read on every call so changes apply to
+// the running chat.
+pub fn load_chat(
+    web_client: &mut WebClient,
+    sender: &Sender<NetworkMessage>,
+    receiver: &Receiver<DatabaseMessage>,
+    addr: &str,
+    chat_id: &str,
+) {
+    if web_client.chat_id != chat_id {
+        let _ = sender.send(NetworkMessage::ChatRequest(
+            addr.to_string(),
+            chat_id.to_string(),
+        ));
+        if let DatabaseMessage::Messages(_id, messages) = reply(receiver) {
+            let _ = sender.send(NetworkMessage::GetSummary(
+                addr.to_string(),
+                chat_id.to_string(),
+            ));
+            let summary = match reply(receiver) {
+                DatabaseMessage::Summary(summary) => Some(summary),
+                _ => None,
+            };
+            web_client.load_context(Messages::new(messages), summary);
+            web_client.chat_id = chat_id.to_string();
+        }
+    }
+    let _ = sender.send(NetworkMessage::GetSystemPrompt(
+        addr.to_string(),
+        chat_id.to_string(),
+    ));
+    web_client.system_prompt = match reply(receiver) {
+        DatabaseMessage::SystemPrompt(prompt) => Some(prompt),
+        _ => None,
+    };
+    let _ = sender.send(NetworkMessage::GetChatSettings(
+        addr.to_string(),
+        chat_id.to_string(),
+    ));
+    web_client.settings = match reply(receiver) {
+        DatabaseMessage::ChatSettings(settings) => Some(settings),
+        _ => None,
+    };
+}
+
+// The base64 encoded speech of message `id`, read from the cache when it was
+// synthesized before. Only a miss loads the text of the message, failing with
+// the reply of the database when it may not be read.
+pub fn audio_file(
+    web_client: &mut WebClient,
+    sender: &Sender<NetworkMessage>,
+    receiver: &Receiver<DatabaseMessage>,
+    addr: &str,
+    id: &str,
+) -> Result<Option<String>, Box<DatabaseMessage>> {
+    let _ = sender.send(NetworkMessage::GetAudioPath(
+        addr.to_string(),
+        id.to_string(),
+    ));
+    if let DatabaseMessage::AudioPath(path) = reply(receiver) {
+        if let Ok(audio) = std::fs::read_to_string(path) {
+            return Ok(Some(audio));
         }
     }
+    let _ = sender.send(NetworkMessage::GetMessage(addr.to_string(), id.to_string()));
+    let message = match reply(receiver) {
+        DatabaseMessage::Message(message) => message.content.unwrap_or_default(),
+        response => return Err(Box::new(response)),
+    };
+    Ok(synthesize_audio(web_client, sender, addr, id, &message))
+}
+
+// Synthesizes `message` and caches the result under `static/` so later
+// requests for the same message skip the TTS call.
+fn synthesize_audio(
+    web_client: &mut WebClient,
+    sender: &Sender<NetworkMessage>,
+    addr: &str,
+    id: &str,
+    message: &str,
+) -> Option<String> {
+    let audio = web_client.new_audio(message.to_string())?;
+    let path = format!("static/{}", id);
+    let _ = std::fs::create_dir_all("static");
+    if std::fs::write(&path, &audio).is_ok() {
+        let _ = sender.send(NetworkMessage::RecordAudioPath(
+            addr.to_string(),
+            id.to_string(),
+            path,
+        ));
+    }
+    Some(audio)
+}
+
+// Drains the frames that arrived while `generating` was streaming. A matching
+// cancel stops the generation, and so does the client going away, which also
+// sets `closed`. Anything else is queued for `update`.
+fn cancel_requested(
+    reader: &mut Reader<TcpStream>,
+    pending: &mut VecDeque<OwnedMessage>,
+    closed: &mut bool,
+    version: u32,
+    generating: &NewMessage,
+) -> bool {
+    let mut cancelled = false;
+    loop {
+        match reader.recv_message() {
+            Ok(OwnedMessage::Text(data)) => {
+                if let Ok(ClientMessage {
+                    body: ClientMessageKind::Cancel(ref cancel),
+                    ..
+                }) = protocol::parse(version, &data)
+                {
+                    if cancel.chat_id == generating.chat_id {
+                        cancelled = true;
+                        continue;
+                    }
+                }
+                pending.push_back(OwnedMessage::Text(data));
+            }
+            Ok(OwnedMessage::Close(_)) => {
+                *closed = true;
+                return true;
+            }
+            Ok(message @ OwnedMessage::Binary(_)) => pending.push_back(message),
+            Ok(_) => continue,
+            Err(error) => {
+                if disconnected(&error) {
+                    *closed = true;
+                    return true;
+                }
+                break;
+            }
+        }
+    }
+    cancelled
+}
+
+// Whether a read error means the client is gone, rather than that the
+// non-blocking socket has nothing to read yet.
+fn disconnected(error: &WebSocketError) -> bool {
+    !matches!(error, WebSocketError::IoError(e) if e.kind() == ErrorKind::WouldBlock)
 }
 
 fn read(stream: &mut TcpStream) -> Vec<u8> {
@@ -1,17 +1,31 @@
-use serde::{Deserialize, Serialize};
+use crate::modules::tokenizer::tokenizer::Tokenizer;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::json;
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Message {
     pub content: Option<String>,
     pub role: Option<String>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub struct WebMessage {
     pub message: Message,
-    created_at: u64,
+    pub created_at: u64,
     pub id: String,
+    #[serde(default)]
+    pub truncated: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    /// Ids of every message sharing this one's parent, itself included, when
+    /// there is more than one. These are the branches a client can switch to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub siblings: Vec<String>,
 }
 
 impl WebMessage {
@@ -20,6 +34,11 @@ impl WebMessage {
             message,
             created_at,
             id,
+            truncated: false,
+            usage: None,
+            model: None,
+            parent_id: None,
+            siblings: Vec::new(),
         }
     }
 }
@@ -33,7 +52,7 @@ impl Message {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
 pub struct Usage {
     #[serde(rename = "prompt_tokens")]
     pub prompt_tokens: i32,
@@ -92,36 +111,198 @@ impl Messages {
         self.messages.push(message);
     }
 
-    pub fn get_window(&self, content_len: u64) -> Vec<WebMessage> {
-        let max_size = self.max_tokens - (self.answer_tokens + content_len);
-        let mut count = 0;
+    pub fn used_tokens(&self) -> u64 {
+        self.used_tokens
+    }
+
+    /// The most recent messages that fit in `max_tokens` while leaving
+    /// `answer_tokens` and `reserved` free. The latest message is always kept.
+    pub fn get_window(&mut self, tokenizer: &dyn Tokenizer, reserved: u64) -> Vec<WebMessage> {
+        let max_size = self.max_tokens.saturating_sub(self.answer_tokens);
+        let mut count = reserved;
         let mut messages = Vec::new();
         for message in self.messages.iter().rev() {
-            if count >= max_size {
+            let tokens = tokenizer.count_message(&message.message);
+            if count + tokens > max_size && !messages.is_empty() {
                 break;
             }
-
-            count += json!(message).to_string().len() as u64;
+            count += tokens;
             messages.push(message.clone());
         }
         messages.reverse();
+        self.used_tokens = count;
         messages
     }
 }
 
+/// Sampling parameters of a chat. Unset fields use the provider defaults.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct Sampling {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    #[serde(
+        default,
+        deserialize_with = "stop_sequences",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub stop: Option<Vec<String>>,
+}
+
+// OpenAI's API takes a single stop sequence as a plain string.
+fn stop_sequences<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stop {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Option::<Stop>::deserialize(deserializer)? {
+        Some(Stop::One(stop)) => Some(vec![stop]),
+        Some(Stop::Many(stops)) => Some(stops),
+        None => None,
+    })
+}
+
+impl Sampling {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !self.temperature.is_none_or(|x| (0.0..=2.0).contains(&x)) {
+            return Err("temperature must be between 0 and 2");
+        }
+        if !self.top_p.is_none_or(|x| x > 0.0 && x <= 1.0) {
+            return Err("top_p must be greater than 0 and at most 1");
+        }
+        if self.max_tokens == Some(0) {
+            return Err("max_tokens must be greater than 0");
+        }
+        if self.stop.as_ref().is_some_and(|x| x.len() > 4) {
+            return Err("at most 4 stop sequences are allowed");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ChatSettings {
+    pub chat_id: String,
+    pub model: Option<String>,
+    #[serde(flatten)]
+    pub sampling: Sampling,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct GroqTextRequest {
+pub struct ChatCompletionRequest {
     model: String,
     messages: Vec<Message>,
+    stream: bool,
+    #[serde(flatten)]
+    sampling: Sampling,
 }
 
-impl GroqTextRequest {
-    pub fn new(messages: Vec<WebMessage>, model: String) -> Self {
+impl ChatCompletionRequest {
+    pub fn new(messages: Vec<Message>, model: String, sampling: &Sampling) -> Self {
         Self {
             model,
-            messages: messages.iter().map(|x| x.message.clone()).collect::<_>(),
+            messages,
+            stream: false,
+            sampling: sampling.clone(),
         }
     }
+
+    pub fn streaming(mut self) -> Self {
+        self.stream = true;
+        self
+    }
+}
+
+/// A request to `/v1/chat/completions`, in the shape of OpenAI's API.
+#[derive(Debug, Deserialize)]
+pub struct CompletionRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub messages: Vec<CompletionMessage>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(flatten)]
+    pub sampling: Sampling,
+    /// Not part of OpenAI's API. Stores the last user message and the answer
+    /// in this chat of the key's owner.
+    #[serde(default)]
+    pub chat_id: Option<String>,
+}
+
+/// A message of a `CompletionRequest`. Besides a string the content may be a
+/// list of parts, of which only text parts are supported.
+#[derive(Debug, Deserialize)]
+pub struct CompletionMessage {
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub content: Option<CompletionContent>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum CompletionContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ContentPart {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+impl CompletionMessage {
+    /// The message with its text parts joined by newlines.
+    pub fn flatten(self) -> Result<Message, &'static str> {
+        let content = match self.content {
+            Some(CompletionContent::Text(text)) => Some(text),
+            Some(CompletionContent::Parts(parts)) => {
+                let mut texts = Vec::new();
+                for part in parts {
+                    match (part.kind.as_str(), part.text) {
+                        ("text", Some(text)) => texts.push(text),
+                        _ => return Err("only text content parts are supported"),
+                    }
+                }
+                Some(texts.join("\n"))
+            }
+            None => None,
+        };
+        Ok(Message {
+            content,
+            role: self.role,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Delta {
+    pub content: Option<String>,
+    pub role: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChunkChoice {
+    pub index: i32,
+    pub delta: Delta,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    pub created: i32,
+    pub choices: Vec<ChunkChoice>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -130,19 +311,93 @@ pub struct Error {
     message: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Summary of a chat's history up to and including the message `until`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Summary {
+    pub content: String,
+    pub until: String,
+}
+
+impl Summary {
+    pub fn new(content: String, until: String) -> Self {
+        Self { content, until }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ChatEntry {
+    pub chat_id: String,
+    pub title: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub message_count: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ChatTitle {
+    pub chat_id: String,
+    pub title: String,
+}
+
+/// A chat's own system prompt and the persona it uses, if any. When both are
+/// set the persona wins.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ChatPrompt {
+    pub chat_id: String,
+    pub system_prompt: Option<String>,
+    pub persona: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Persona {
+    pub name: String,
+    pub prompt: String,
+}
+
+impl Persona {
+    pub fn new(name: String, prompt: String) -> Self {
+        Self { name, prompt }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct UserInfo {
-    pub token: String,
+    #[serde(flatten)]
+    pub credentials: Credentials,
     name: String,
     email: String,
 }
 
 impl UserInfo {
-    pub fn new(email: String, name: String, token: String) -> Self {
-        Self { email, name, token }
+    pub fn new(email: String, name: String, credentials: Credentials) -> Self {
+        Self {
+            email,
+            name,
+            credentials,
+        }
     }
 }
 
+/// `token` authorizes requests until `expires_at`. After that requests fail
+/// with `token_expired` and `refresh_token` buys a new pair, once.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Credentials {
+    pub token: String,
+    pub refresh_token: String,
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Authenticate {
+    /// An access token or an API key.
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Refresh {
+    pub refresh_token: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub enum SocketMessage {
     Deleted(String),
@@ -150,13 +405,17 @@ pub enum SocketMessage {
     Message(Message),
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct ClientMessage {
-    kind: String,
+    /// Chosen by the client and echoed on every response to this message.
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
     pub body: ClientMessageKind,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "type", content = "payload")]
 pub enum ClientMessageKind {
     #[serde(rename = "login")]
     Login(Login),
@@ -174,63 +433,442 @@ pub enum ClientMessageKind {
     Register(Register),
     #[serde(rename = "get_audio")]
     GetAudio(GetAudio),
+    #[serde(rename = "cancel")]
+    Cancel(Cancel),
+    #[serde(rename = "voice_message")]
+    VoiceMessage(VoiceMessage),
+    #[serde(rename = "set_system_prompt")]
+    SetSystemPrompt(SetSystemPrompt),
+    #[serde(rename = "get_personas")]
+    GetPersonas(String),
+    #[serde(rename = "save_persona")]
+    SavePersona(SavePersona),
+    #[serde(rename = "delete_persona")]
+    DeletePersona(DeletePersona),
+    #[serde(rename = "set_chat_settings")]
+    SetChatSettings(SetChatSettings),
+    #[serde(rename = "get_chat_settings")]
+    GetChatSettings(GetChat),
+    #[serde(rename = "get_models")]
+    GetModels(String),
+    #[serde(rename = "rename_chat")]
+    RenameChat(RenameChat),
+    #[serde(rename = "search")]
+    Search(Search),
+    #[serde(rename = "edit_message")]
+    EditMessage(EditMessage),
+    #[serde(rename = "switch_branch")]
+    SwitchBranch(SwitchBranch),
+    #[serde(rename = "regenerate")]
+    Regenerate(Regenerate),
+    #[serde(rename = "get_sessions")]
+    GetSessions(String),
+    #[serde(rename = "revoke_session")]
+    RevokeSession(RevokeSession),
+    #[serde(rename = "logout_all")]
+    LogoutAll(String),
+    #[serde(rename = "refresh")]
+    Refresh(Refresh),
+    #[serde(rename = "authenticate")]
+    Authenticate(Authenticate),
+    #[serde(rename = "create_api_key")]
+    CreateApiKey(CreateApiKey),
+    #[serde(rename = "get_api_keys")]
+    GetApiKeys(String),
+    #[serde(rename = "revoke_api_key")]
+    RevokeApiKey(RevokeApiKey),
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl ClientMessageKind {
+    /// The token a frame carries. Connections authenticate once, through
+    /// `authenticate`, `login` or `register`. Tokens in later frames are only
+    /// read to authenticate connections of clients that skip the handshake.
+    pub fn token(&self) -> Option<&str> {
+        let token = match self {
+            Self::NewMessage(x) => &x.token,
+            Self::NewChat(x) => &x.token,
+            Self::DeleteChat(x) => &x.token,
+            Self::GetChats(x) | Self::GetPersonas(x) | Self::GetModels(x) => x,
+            Self::GetSessions(x) | Self::LogoutAll(x) | Self::GetApiKeys(x) => x,
+            Self::GetChat(x) | Self::GetChatSettings(x) => &x.token,
+            Self::GetAudio(x) => &x.token,
+            Self::Cancel(x) => &x.token,
+            Self::VoiceMessage(x) => &x.token,
+            Self::SetSystemPrompt(x) => &x.token,
+            Self::SavePersona(x) => &x.token,
+            Self::DeletePersona(x) => &x.token,
+            Self::SetChatSettings(x) => &x.token,
+            Self::RenameChat(x) => &x.token,
+            Self::Search(x) => &x.token,
+            Self::EditMessage(x) => &x.token,
+            Self::SwitchBranch(x) => &x.token,
+            Self::Regenerate(x) => &x.token,
+            Self::RevokeSession(x) => &x.token,
+            Self::CreateApiKey(x) => &x.token,
+            Self::RevokeApiKey(x) => &x.token,
+            Self::Login(_) | Self::Register(_) | Self::Refresh(_) | Self::Authenticate(_) => {
+                return None
+            }
+        };
+        Some(token.as_str()).filter(|x| !x.is_empty())
+    }
+
+    /// Whether the request needs an authenticated connection, which is all
+    /// but the ones that sign in.
+    pub fn requires_authentication(&self) -> bool {
+        !matches!(
+            self,
+            Self::Login(_) | Self::Register(_) | Self::Refresh(_) | Self::Authenticate(_)
+        )
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Login {
     pub email: String,
     pub password: String,
+    /// A label for the device, shown when listing sessions.
+    #[serde(default)]
+    pub device: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct NewMessage {
+    #[serde(default)]
     pub token: String,
     pub chat_id: String,
     pub content: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// The body of `POST /chats/{chat_id}/messages`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct PostMessage {
+    pub content: String,
+}
+
+/// Replaces the user message `message_id` with `content` on a new branch,
+/// leaving the old one and its answers in place.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct EditMessage {
+    #[serde(default)]
+    pub token: String,
+    pub chat_id: String,
+    pub message_id: String,
+    pub content: String,
+}
+
+/// Makes the branch through `message_id` the active one of the chat.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct SwitchBranch {
+    #[serde(default)]
+    pub token: String,
+    pub chat_id: String,
+    pub message_id: String,
+}
+
+/// Answers the last user message of the chat again. The new answer is kept
+/// next to the previous ones, which stay reachable through `switch_branch`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Regenerate {
+    #[serde(default)]
+    pub token: String,
+    pub chat_id: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct NewChat {
+    #[serde(default)]
     pub token: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct DeleteChat {
+    #[serde(default)]
     pub token: String,
     pub chat_id: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct GetAudio {
+    #[serde(default)]
     pub token: String,
     pub message_id: String,
 }
 
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Cancel {
+    #[serde(default)]
+    pub token: String,
+    pub chat_id: String,
+}
+
+/// `audio` holds the base64 encoded recording. When it is missing the
+/// recording is expected in the next binary frame on the socket. With
+/// `speak` set the answer is also synthesized and sent as an `audio` frame.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct VoiceMessage {
+    #[serde(default)]
+    pub token: String,
+    pub chat_id: String,
+    pub audio: Option<String>,
+    pub format: Option<String>,
+    #[serde(default)]
+    pub speak: bool,
+}
+
+/// Recording formats the transcription endpoint accepts.
+pub const AUDIO_FORMATS: [&str; 6] = ["webm", "ogg", "mp3", "wav", "m4a", "flac"];
+
+impl VoiceMessage {
+    pub fn format(&self) -> Result<&str, &'static str> {
+        match self.format.as_deref() {
+            None => Ok("webm"),
+            Some(format) if AUDIO_FORMATS.contains(&format) => Ok(format),
+            Some(_) => Err("format must be one of webm, ogg, mp3, wav, m4a or flac"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct SetSystemPrompt {
+    #[serde(default)]
+    pub token: String,
+    pub chat_id: String,
+    pub system_prompt: Option<String>,
+    pub persona: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct SavePersona {
+    #[serde(default)]
+    pub token: String,
+    pub name: String,
+    pub prompt: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DeletePersona {
+    #[serde(default)]
+    pub token: String,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct SetChatSettings {
+    #[serde(default)]
+    pub token: String,
+    pub chat_id: String,
+    pub model: Option<String>,
+    #[serde(flatten)]
+    pub sampling: Sampling,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct RenameChat {
+    #[serde(default)]
+    pub token: String,
+    pub chat_id: String,
+    pub title: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Search {
+    #[serde(default)]
+    pub token: String,
+    pub query: String,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+/// A message matching a search. Matched terms in `snippet` are wrapped in `**`.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct SearchResult {
+    pub chat_id: String,
+    pub chat_title: Option<String>,
+    pub message_id: String,
+    pub snippet: String,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct SearchResults {
+    pub query: String,
+    pub results: Vec<SearchResult>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ValidateToken {
     pub token: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct GetChat {
+    #[serde(default)]
     pub token: String,
     pub chat_id: String,
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub direction: Direction,
+    #[serde(default)]
+    pub limit: Option<u64>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Which side of the cursor a page is read from. Without a cursor `Before`
+/// yields the newest page and `After` the oldest one.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub enum Direction {
+    #[default]
+    #[serde(rename = "before")]
+    Before,
+    #[serde(rename = "after")]
+    After,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PageRequest {
+    pub cursor: Option<String>,
+    pub direction: Direction,
+    pub limit: u64,
+}
+
+/// Messages in chronological order. `has_more` tells whether more messages
+/// exist past this page in the requested direction.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct MessagePage {
+    pub chat_id: String,
+    pub messages: Vec<WebMessage>,
+    pub has_more: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Register {
     pub email: String,
     pub password: String,
     pub name: String,
+    #[serde(default)]
+    pub device: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// A signed in device. `current` marks the session the request came from.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Session {
+    pub id: String,
+    pub device: Option<String>,
+    pub created_at: u64,
+    pub last_seen: u64,
+    pub expires_at: u64,
+    pub current: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct RevokeSession {
+    #[serde(default)]
+    pub token: String,
+    pub session_id: String,
+}
+
+/// What an API key may be used for. Managing sessions and keys always takes
+/// a signed in session.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Reading chats, messages, audio, personas and settings.
+    Read,
+    /// Creating, changing and deleting chats, messages, personas and settings.
+    Write,
+    /// The OpenAI compatible `/v1` endpoints. Sessions never hold it.
+    Completions,
+}
+
+/// A personal API key. It authenticates like an access token, limited to
+/// its `scopes`, until it expires or is revoked.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: u64,
+    pub last_used: Option<u64>,
+    pub expires_at: Option<u64>,
+}
+
+/// A freshly created key. This is the only time `key` is shown, only a hash
+/// of it is stored.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct NewApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub info: ApiKey,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct CreateApiKey {
+    #[serde(default)]
+    pub token: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Unix time after which the key stops working. Never when missing.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+impl CreateApiKey {
+    pub fn validate(&self, now: u64) -> Result<(), &'static str> {
+        let name = self.name.trim();
+        if name.is_empty() || name.chars().count() > 100 {
+            return Err("Key names take 1 to 100 characters");
+        }
+        if self.scopes.is_empty() {
+            return Err("A key needs at least one scope");
+        }
+        if self.expires_at.is_some_and(|x| x <= now) {
+            return Err("expires_at must be in the future");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct RevokeApiKey {
+    #[serde(default)]
+    pub token: String,
+    pub key_id: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct AudioInfo {
     pub message_id: String,
     pub content: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+pub struct TranscriptionResponse {
+    pub text: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Transcript {
+    pub chat_id: String,
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct StreamStart {
+    pub chat_id: String,
+    pub message_id: String,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct StreamDelta {
+    pub chat_id: String,
+    pub message_id: String,
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "type", content = "payload")]
 pub enum ServerResponse {
     #[serde(rename = "token")]
     Token(String),
@@ -241,11 +879,90 @@ pub enum ServerResponse {
     #[serde(rename = "message")]
     Message(WebMessage),
     #[serde(rename = "chats")]
-    Chats(Vec<String>),
+    Chats(Vec<ChatEntry>),
     #[serde(rename = "messages")]
-    Messages(Vec<WebMessage>),
+    Messages(MessagePage),
     #[serde(rename = "audio")]
     Audio(AudioInfo),
     #[serde(rename = "deleted")]
     Deleted(String),
+    #[serde(rename = "message_start")]
+    MessageStart(StreamStart),
+    #[serde(rename = "message_delta")]
+    MessageDelta(StreamDelta),
+    #[serde(rename = "message_done")]
+    MessageDone(WebMessage),
+    #[serde(rename = "transcript")]
+    Transcript(Transcript),
+    #[serde(rename = "system_prompt")]
+    SystemPrompt(ChatPrompt),
+    #[serde(rename = "personas")]
+    Personas(Vec<Persona>),
+    #[serde(rename = "chat_settings")]
+    ChatSettings(ChatSettings),
+    #[serde(rename = "models")]
+    Models(Vec<String>),
+    #[serde(rename = "chat_title")]
+    ChatTitle(ChatTitle),
+    #[serde(rename = "search_results")]
+    SearchResults(SearchResults),
+    #[serde(rename = "sessions")]
+    Sessions(Vec<Session>),
+    #[serde(rename = "credentials")]
+    Credentials(Credentials),
+    #[serde(rename = "authenticated")]
+    Authenticated(String),
+    #[serde(rename = "api_key_created")]
+    ApiKeyCreated(NewApiKey),
+    #[serde(rename = "api_keys")]
+    ApiKeys(Vec<ApiKey>),
+    #[serde(rename = "error")]
+    Error {
+        request_id: Option<String>,
+        code: ErrorCode,
+        message: String,
+        retryable: bool,
+    },
+}
+
+/// A frame the server sends: a response, with the `request_id` of the
+/// request it answers. Frames the server pushes on its own carry none.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ServerFrame {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub response: ServerResponse,
+}
+
+/// Why a request failed. `message` in the error frame is meant for people,
+/// clients branch on the code.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame could not be parsed or a field is invalid.
+    BadRequest,
+    /// The connection is not authenticated or the credentials are wrong.
+    Unauthorized,
+    /// The access token expired. Refresh it and send the request again.
+    TokenExpired,
+    /// The session was revoked. The server closes the connection after this.
+    SessionRevoked,
+    /// The chat or message does not exist or belongs to someone else, or the
+    /// API key the connection authenticated with lacks the scope for it.
+    Forbidden,
+    /// There is nothing to act on, like cancelling when nothing is generating.
+    NotFound,
+    /// The email is already registered.
+    Conflict,
+    /// The model, speech or transcription provider failed.
+    Upstream,
+}
+
+impl ErrorCode {
+    /// Whether the same request may succeed later. After `token_expired`
+    /// only once the token has been refreshed.
+    pub fn retryable(self) -> bool {
+        matches!(self, Self::TokenExpired | Self::Upstream)
+    }
 }